@@ -0,0 +1,206 @@
+use std::error::Error;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::result::Result as StdResult;
+
+use chrono::{DateTime, Utc};
+use rocket::serde::json;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::auth::User;
+
+const FILE: &str = "data/audit.log";
+
+type Result<T> = StdResult<T, Box<dyn Error>>;
+
+/// An administrative action recorded in the audit log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, FromFormField)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    #[field(value = "clone_repo")]
+    CloneRepo,
+    Fetch,
+    Pull,
+    Push,
+    Checkout,
+    #[field(value = "update_settings")]
+    UpdateSettings,
+    #[field(value = "update_repo_settings")]
+    UpdateRepoSettings,
+    #[field(value = "start_session")]
+    StartSession,
+    #[field(value = "finish_session")]
+    FinishSession,
+    #[field(value = "edit_session")]
+    EditSession,
+    #[field(value = "merge_session")]
+    MergeSession,
+    #[field(value = "create_user")]
+    CreateUser,
+    #[field(value = "update_user")]
+    UpdateUser,
+    #[field(value = "delete_user")]
+    DeleteUser,
+    #[field(value = "create_invite")]
+    CreateInvite,
+    #[field(value = "delete_invite")]
+    DeleteInvite,
+}
+
+impl Action {
+    pub const ALL: [Action; 16] = [
+        Action::CloneRepo, Action::Fetch, Action::Pull, Action::Push, Action::Checkout,
+        Action::UpdateSettings, Action::UpdateRepoSettings, Action::StartSession, Action::FinishSession, Action::EditSession,
+        Action::MergeSession,
+        Action::CreateUser, Action::UpdateUser, Action::DeleteUser, Action::CreateInvite, Action::DeleteInvite,
+    ];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, FromFormField)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub date: DateTime<Utc>,
+    pub user_id: Uuid,
+    pub user: String,
+    pub action: Action,
+    pub details: String,
+    pub outcome: Outcome,
+    pub message: String,
+}
+
+/// Criteria to search the audit log with, all of them optional
+#[derive(Debug, Default, FromForm)]
+pub struct Filter {
+    pub user: Option<String>,
+    pub action: Option<Action>,
+    pub outcome: Option<Outcome>,
+}
+
+impl Filter {
+    fn matches(&self, entry: &Entry) -> bool {
+        self.user.as_deref().map(str::trim).filter(|u| !u.is_empty()).is_none_or(|u| entry.user == u)
+            && self.action.is_none_or(|a| entry.action == a)
+            && self.outcome.is_none_or(|o| entry.outcome == o)
+    }
+}
+
+fn append<P: AsRef<Path>>(path: P, entry: &Entry) -> Result<()> {
+    let mut line = json::to_string(entry)?;
+    line.push('\n');
+
+    if let Some(parent) = path.as_ref().parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    // A single write, so concurrent appends don't get interleaved
+    file.write_all(line.as_bytes())?;
+
+    Ok(())
+}
+
+fn search<P: AsRef<Path>>(path: P, filter: &Filter, limit: usize) -> Result<Vec<Entry>> {
+    let path = path.as_ref();
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    let log = fs::read_to_string(path)?;
+    let entries = log.lines()
+        .rev()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match json::from_str::<Entry>(line) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                eprintln!("Skipping invalid audit log entry: {e}");
+                None
+            }
+        })
+        .filter(|entry| filter.matches(entry))
+        .take(limit)
+        .collect();
+
+    Ok(entries)
+}
+
+/// Record the outcome of an action done by the given user
+///
+/// Failing to write the log doesn't undo the action, so errors are only printed
+pub fn record(user: &User, action: Action, details: &str, result: &StdResult<String, String>) {
+    let (outcome, message) = match result {
+        Ok(msg) => (Outcome::Success, msg),
+        Err(msg) => (Outcome::Failure, msg),
+    };
+
+    let entry = Entry {
+        date: Utc::now(),
+        user_id: user.id,
+        user: user.name.clone(),
+        action,
+        details: details.to_string(),
+        outcome,
+        message: message.clone(),
+    };
+
+    if let Err(e) = append(FILE, &entry) {
+        eprintln!("Failed to write to the audit log: {e}, entry: {entry:?}");
+    }
+}
+
+/// The most recent entries matching the filter, newest first
+pub fn search_log(filter: &Filter, limit: usize) -> Result<Vec<Entry>> {
+    search(FILE, filter, limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn entry(user: &str, action: Action, outcome: Outcome) -> Entry {
+        Entry {
+            date: Utc::now(),
+            user_id: Uuid::new_v4(),
+            user: user.to_string(),
+            action,
+            details: String::new(),
+            outcome,
+            message: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_search() {
+        let dir = tempdir().expect("Failed to create temp dir");
+        let path = dir.path().join("data/audit.log");
+
+        assert!(search(&path, &Filter::default(), 10).expect("Failed to search").is_empty());
+
+        append(&path, &entry("alice", Action::Fetch, Outcome::Success)).expect("Failed to append");
+        append(&path, &entry("bob", Action::Checkout, Outcome::Failure)).expect("Failed to append");
+        append(&path, &entry("alice", Action::Checkout, Outcome::Success)).expect("Failed to append");
+
+        let all = search(&path, &Filter::default(), 10).expect("Failed to search");
+        assert_eq!(3, all.len());
+        assert_eq!(("alice", Action::Checkout), (all[0].user.as_str(), all[0].action), "Not newest first");
+
+        let filter = Filter { user: Some("alice".to_string()), ..Filter::default() };
+        assert_eq!(2, search(&path, &filter, 10).expect("Failed to search").len());
+
+        let filter = Filter { action: Some(Action::Checkout), outcome: Some(Outcome::Failure), ..Filter::default() };
+        let entries = search(&path, &filter, 10).expect("Failed to search");
+        assert_eq!(1, entries.len());
+        assert_eq!("bob", entries[0].user);
+
+        assert_eq!(1, search(&path, &Filter::default(), 1).expect("Failed to search").len());
+    }
+}
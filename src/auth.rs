@@ -0,0 +1,279 @@
+use std::convert::Infallible;
+use std::error::Error;
+use std::net::IpAddr;
+
+use chrono::{DateTime, Duration, Utc};
+use rocket::http::{CookieJar, Status};
+use rocket::outcome::IntoOutcome;
+use rocket::outcome::Outcome::{Forward, Success};
+use rocket::{Config, Request};
+use rocket::figment::Figment;
+use rocket::figment::providers::{Env, Format, Toml};
+use rocket::figment::Profile;
+use rocket::request::{FromRequest, Outcome};
+use uuid::Uuid;
+
+use crate::{csrf, Logins, Users};
+use crate::logins::LoginStore;
+use crate::users::{Account, Role};
+
+const SESSION_COOKIE: &str = "session";
+const PENDING_LOGIN_COOKIE: &str = "pending_login";
+/// Time allowed to enter the second factor after entering the password
+const PENDING_LOGIN_MINUTES: i64 = 5;
+
+/// Any logged in user, authenticated either by the session cookie or by an API token
+///
+/// State-changing requests authenticated by the cookie also need a valid CSRF token
+#[derive(Debug)]
+pub struct User {
+    pub id: Uuid,
+    pub name: String,
+    pub role: Role,
+    /// The server-side login of the session cookie, if authenticated by it
+    pub login: Option<Uuid>,
+    /// Whether the account has a second factor enrolled
+    pub two_factor: bool,
+}
+
+/// Where a request comes from, recorded with new logins
+///
+/// The address is the one of the connection, unless the `ip_header` of a reverse proxy is configured, see [`figment`]
+#[derive(Debug)]
+pub struct Client {
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+/// A user authenticated by an API token in the `Authorization: Bearer <token>` header
+#[derive(Debug)]
+pub struct BearerUser(pub User);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for User {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let token = request.cookies().get_private(SESSION_COOKIE);
+        let (token, logins, users) = match (token, request.rocket().state::<Logins>(), request.rocket().state::<Users>()) {
+            (Some(_), _, _) if !csrf::verify(request) => return Forward(Status::Forbidden),
+            (Some(token), Some(logins), Some(users)) => (token, logins, users),
+            _ => return request.guard::<BearerUser>().await.map(|u| u.0)
+        };
+
+        let login = logins.lock().await
+            .authenticate(token.value())
+            .map(|l| (l.id, l.user_id));
+        let (login_id, user_id) = match login {
+            Some(login) => login,
+            None => return Forward(Status::Unauthorized)
+        };
+
+        users.lock().await
+            .get(user_id)
+            .filter(|u| !u.disabled)
+            .map(|u| User::new(u, Some(login_id)))
+            .or_forward(Status::Unauthorized)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for BearerUser {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let token = request.headers()
+            .get_one("Authorization")
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(str::trim);
+
+        match (token, request.rocket().state::<Users>()) {
+            (Some(token), Some(users)) => users.lock().await
+                .authenticate_token(token)
+                .map(|u| BearerUser(User::new(u, None)))
+                .or_forward(Status::Unauthorized),
+            _ => Forward(Status::Unauthorized)
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Client {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Success(Client {
+            ip: request.real_ip().or_else(|| request.remote().map(|a| a.ip())),
+            user_agent: request.headers().get_one("User-Agent").map(str::to_string),
+        })
+    }
+}
+
+/// Rocket's configuration, without an `ip_header` unless the operator configures one
+///
+/// Rocket trusts `X-Real-IP` by default, which any client could set to dodge the login throttle
+pub fn figment() -> Figment {
+    let config = Config {
+        ip_header: None,
+        ..Config::default()
+    };
+
+    Figment::from(config)
+        .merge(Toml::file(Env::var_or("ROCKET_CONFIG", "Rocket.toml")).nested())
+        .merge(Env::prefixed("ROCKET_").ignore(&["PROFILE"]).global())
+        .select(Profile::from_env_or("ROCKET_PROFILE", Config::DEFAULT_PROFILE))
+}
+
+/// A user who entered a valid password, but still needs to enter their second factor
+///
+/// Like [`User`], state-changing requests also need a valid CSRF token
+#[derive(Debug)]
+pub struct PendingLogin(pub Uuid);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for PendingLogin {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        if !csrf::verify(request) {
+            return Forward(Status::Forbidden);
+        }
+
+        request.cookies()
+            .get_private(PENDING_LOGIN_COOKIE)
+            .and_then(|cookie| {
+                let (id, date) = cookie.value().split_once(' ')?;
+                let date: DateTime<Utc> = date.parse().ok()?;
+                if Utc::now() - date > Duration::minutes(PENDING_LOGIN_MINUTES) {
+                    return None;
+                }
+
+                id.parse().ok()
+            })
+            .map(PendingLogin)
+            .or_forward(Status::Unauthorized)
+    }
+}
+
+/// Log in as the given user, recording a new server-side login and renewing the CSRF token
+///
+/// Returns the new CSRF token, for pages rendered in response to the login
+pub fn log_in(cookies: &CookieJar<'_>, logins: &mut LoginStore, client: Client, id: Uuid) -> Result<String, Box<dyn Error>> {
+    let token = logins.create(id, client.ip, client.user_agent)?;
+    cookies.remove_private(PENDING_LOGIN_COOKIE);
+    cookies.add_private((SESSION_COOKIE, token));
+
+    Ok(csrf::renew_token(cookies))
+}
+
+/// Remember a user who still needs to enter their second factor
+pub fn start_pending_login(cookies: &CookieJar<'_>, id: Uuid) {
+    cookies.add_private((PENDING_LOGIN_COOKIE, format!("{id} {}", Utc::now().to_rfc3339())));
+}
+
+/// Log out, revoking the server-side login of the session cookie
+pub fn log_out(cookies: &CookieJar<'_>, logins: &mut LoginStore) -> Result<(), Box<dyn Error>> {
+    cookies.remove_private(PENDING_LOGIN_COOKIE);
+    if let Some(token) = cookies.get_private(SESSION_COOKIE) {
+        cookies.remove_private(SESSION_COOKIE);
+        logins.revoke_token(token.value())?;
+    }
+
+    Ok(())
+}
+
+impl User {
+    fn new(account: &Account, login: Option<Uuid>) -> User {
+        User {
+            id: account.id,
+            name: account.name.clone(),
+            role: account.role,
+            login,
+            two_factor: account.has_two_factor(),
+        }
+    }
+
+    pub fn has_role(&self, role: Role) -> bool {
+        self.role >= role
+    }
+}
+
+/// Whether the (optional) user has at least the given role
+pub fn has_role(user: &Option<User>, role: Role) -> bool {
+    user.as_ref().is_some_and(|u| u.has_role(role))
+}
+
+/// Declare a request guard for users with at least the given role, and optionally a second factor
+macro_rules! role_guard {
+    ($(#[$attr:meta])* $name:ident, $role:expr) => {
+        role_guard!($(#[$attr])* $name, $role, false);
+    };
+    ($(#[$attr:meta])* $name:ident, $role:expr, $two_factor:expr) => {
+        $(#[$attr])*
+        #[derive(Debug)]
+        pub struct $name(pub User);
+
+        #[rocket::async_trait]
+        impl<'r> FromRequest<'r> for $name {
+            type Error = Infallible;
+
+            async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+                match request.guard::<User>().await {
+                    Success(user) if user.has_role($role) && (user.two_factor || !$two_factor) => Success($name(user)),
+                    _ => Forward(Status::Unauthorized)
+                }
+            }
+        }
+    };
+}
+
+role_guard!(
+    /// A user with the session host role or higher
+    HostUser, Role::SessionHost
+);
+role_guard!(
+    /// A user with the admin role, who has a second factor enrolled
+    ///
+    /// Admins without one, i.e. just promoted, must log in again and set it up first. Their API tokens only work
+    /// for admin routes once they have one
+    AdminUser, Role::Admin, true
+);
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use rocket::http::{Header, Status};
+    use rocket::local::blocking::Client as LocalClient;
+    use rocket::State;
+
+    use crate::LoginAttempts;
+
+    use super::*;
+
+    #[post("/attempt/<user>")]
+    async fn attempt(user: &str, client: Client, attempts: &State<LoginAttempts>) -> Status {
+        match attempts.lock().await.reserve(client.ip, user, Utc::now()) {
+            Ok(_) => Status::Ok,
+            Err(_) => Status::TooManyRequests,
+        }
+    }
+
+    #[test]
+    fn test_forged_ip() {
+        let rocket = rocket::custom(figment())
+            .mount("/", routes![attempt])
+            .manage(LoginAttempts::default());
+        let client = LocalClient::untracked(rocket).expect("Failed to start rocket");
+
+        // Guessing for different accounts with a new address every time still runs into the throttle of the real one
+        let blocked = (0..20).map(|i| {
+            client.post(format!("/attempt/user{i}"))
+                .remote("192.0.2.1:1234".parse().unwrap())
+                .header(Header::new("X-Real-IP", format!("10.0.0.{i}")))
+                .dispatch()
+                .status()
+        }).position(|status| status == Status::TooManyRequests);
+
+        assert!(blocked.is_some(), "The forged addresses dodged the throttle");
+    }
+}
@@ -0,0 +1,121 @@
+use std::convert::Infallible;
+
+use rocket::{Data, Request};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::form::Form;
+use rocket::http::{CookieJar, RawStr, Status};
+use rocket::outcome::Outcome::{Forward, Success};
+use rocket::request::{FromRequest, Outcome};
+
+use crate::util;
+
+const COOKIE: &str = "csrf";
+const FIELD: &str = "csrf_token";
+const HEADER: &str = "X-CSRF-Token";
+const TOKEN_LENGTH: usize = 32;
+/// Only the start of the body is read, so the token field must be the first one in the forms
+const PEEK_LENGTH: usize = 512;
+
+/// The CSRF token of the current browser session, to be embedded in the forms as a hidden `csrf_token` field
+#[derive(Debug)]
+pub struct CsrfToken(pub String);
+
+/// Only succeeds if the request carries the CSRF token of the current browser session
+#[derive(Debug)]
+pub struct VerifiedCsrf;
+
+/// The token submitted in the body of the request, found by [`CsrfFairing`]
+struct SubmittedToken(Option<String>);
+
+pub struct CsrfFairing;
+
+/// Replace the CSRF token, i.e. when the user logs in
+pub fn renew_token(cookies: &CookieJar<'_>) -> String {
+    let token = util::random_string(TOKEN_LENGTH);
+    cookies.add_private((COOKIE, token.clone()));
+    token
+}
+
+/// Check if a state-changing request carries the expected CSRF token, either in the `X-CSRF-Token` header
+/// or in the form body
+pub fn verify(request: &Request<'_>) -> bool {
+    if !request.method().supports_payload() {
+        return true;
+    }
+
+    let expected = request.cookies().get_private(COOKIE);
+    let submitted = request.headers().get_one(HEADER)
+        .or(request.local_cache(|| SubmittedToken(None)).0.as_deref());
+
+    match (expected, submitted) {
+        (Some(expected), Some(submitted)) => util::constant_time_eq(expected.value(), submitted),
+        _ => false
+    }
+}
+
+fn find_token(body: &str) -> Option<String> {
+    Form::values(body)
+        .find(|field| field.name == FIELD)
+        .map(|field| RawStr::new(field.value).url_decode_lossy().into_owned())
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CsrfToken {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let cookies = request.cookies();
+        let token = match cookies.get_private(COOKIE) {
+            Some(cookie) => cookie.value().to_string(),
+            None => renew_token(cookies),
+        };
+
+        Success(CsrfToken(token))
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for VerifiedCsrf {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        if verify(request) {
+            Success(VerifiedCsrf)
+        } else {
+            Forward(Status::Forbidden)
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for CsrfFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "CSRF token",
+            kind: Kind::Request,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, data: &mut Data<'_>) {
+        if !request.method().supports_payload() || !request.content_type().is_some_and(|t| t.is_form()) {
+            return;
+        }
+
+        let token = find_token(&String::from_utf8_lossy(data.peek(PEEK_LENGTH).await));
+        request.local_cache(|| SubmittedToken(token));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_token() {
+        assert_eq!(Some("abc123".to_string()), find_token("csrf_token=abc123&name=foo"));
+        assert_eq!(Some("abc123".to_string()), find_token("name=foo&csrf_token=abc123"));
+        assert_eq!(Some("a b".to_string()), find_token("csrf_token=a+b"));
+        assert_eq!(None, find_token("name=foo&password=bar"));
+        assert_eq!(None, find_token(""));
+    }
+}
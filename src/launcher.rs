@@ -0,0 +1,244 @@
+use std::error::Error;
+use std::path::Path;
+use std::process::Command;
+
+use serde::{Deserialize, Serialize};
+
+use crate::settings::Settings;
+use crate::util::throw;
+
+/// How the Enigma server of a session is started
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, FromFormField)]
+#[serde(rename_all = "snake_case")]
+pub enum LauncherKind {
+    /// `java` with the configured classpath and main class
+    #[default]
+    Java,
+    /// A Gradle task of the mappings repository, which sets up the classpath itself
+    Gradle,
+    /// Any shell command, given the parameters as environment variables
+    Command,
+}
+
+impl LauncherKind {
+    pub const ALL: [LauncherKind; 3] = [LauncherKind::Java, LauncherKind::Gradle, LauncherKind::Command];
+}
+
+/// What the Enigma server needs to serve a session
+#[derive(Debug)]
+pub struct LaunchParams<'a> {
+    /// Working tree of the session, the command is run in it
+    pub dir: &'a Path,
+    /// Absolute, since the jar is in the main repository rather than in the working tree
+    pub jar: &'a str,
+    pub mappings: &'a str,
+    pub port: u16,
+    pub password: Option<&'a str>,
+}
+
+/// Builds the command starting an Enigma server
+///
+/// Output, working directory and process group are set up by the caller
+pub trait Launcher {
+    fn command(&self, params: &LaunchParams) -> Command;
+}
+
+/// Runs the main class of the Enigma server with `java`
+pub struct JavaLauncher {
+    pub classpath: String,
+    pub main_class: String,
+    /// Extra arguments for the Enigma server, separated by spaces
+    pub args: String,
+}
+
+/// Runs a Gradle task passing the Enigma server arguments through `--args`, like `./gradlew enigma --args=...`
+///
+/// The Gradle wrapper of the working tree is used if there's one. The daemon is disabled, so stopping the
+/// session also stops the server
+pub struct GradleLauncher {
+    pub task: String,
+    /// Extra arguments for the Enigma server, separated by spaces
+    pub args: String,
+}
+
+/// Runs a shell command, with the parameters in the `ENIGMA_JAR`, `ENIGMA_MAPPINGS`, `ENIGMA_PORT` and
+/// `ENIGMA_PASSWORD` environment variables
+pub struct CommandLauncher {
+    pub command: String,
+}
+
+/// The launcher selected in the settings
+pub fn from_settings(settings: &Settings) -> Result<Box<dyn Launcher>, Box<dyn Error>> {
+    Ok(match settings.launcher {
+        LauncherKind::Java => Box::new(JavaLauncher {
+            classpath: settings.classpath.clone(),
+            main_class: settings.enigma_main_class.clone(),
+            args: settings.enigma_args.clone(),
+        }),
+        LauncherKind::Gradle if settings.gradle_task.trim().is_empty() => throw!("No Gradle task configured"),
+        LauncherKind::Gradle => Box::new(GradleLauncher {
+            task: settings.gradle_task.trim().to_string(),
+            args: settings.enigma_args.clone(),
+        }),
+        LauncherKind::Command if settings.launch_command.trim().is_empty() => throw!("No launch command configured"),
+        LauncherKind::Command => Box::new(CommandLauncher {
+            command: settings.launch_command.clone(),
+        }),
+    })
+}
+
+/// The arguments of the Enigma server, followed by the extra ones
+fn server_args(params: &LaunchParams, extra: &str) -> Vec<String> {
+    let mut args = vec![
+        "-jar".to_string(), params.jar.to_string(),
+        "-mappings".to_string(), params.mappings.to_string(),
+        "-port".to_string(), params.port.to_string(),
+    ];
+    if let Some(password) = params.password {
+        args.push("-password".to_string());
+        args.push(password.to_string());
+    }
+    args.extend(extra.split_whitespace().map(str::to_string));
+
+    args
+}
+
+/// Check that a session password can be passed to the Enigma server by the given launcher
+///
+/// Gradle's `--args` can't escape quotes, so an argument can't contain both `"` and `'` there
+pub fn check_password(kind: LauncherKind, password: &str) -> Result<(), String> {
+    if kind == LauncherKind::Gradle && password.contains('"') && password.contains('\'') {
+        Err("Passwords can't contain both \" and ' with the Gradle launcher".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+/// Quote an argument for Gradle's `--args`, which splits on whitespace unless quoted
+///
+/// Arguments with both kinds of quotes can't be quoted, see [`check_password`]
+fn quote_gradle_arg(arg: &str) -> String {
+    if arg.is_empty() || arg.contains(char::is_whitespace) || arg.contains(['"', '\'']) {
+        if arg.contains('"') {
+            format!("'{arg}'")
+        } else {
+            format!("\"{arg}\"")
+        }
+    } else {
+        arg.to_string()
+    }
+}
+
+impl Launcher for JavaLauncher {
+    fn command(&self, params: &LaunchParams) -> Command {
+        let mut command = Command::new("java");
+        if !self.classpath.is_empty() {
+            command.arg("-cp").arg(&self.classpath);
+        }
+        command.arg(&self.main_class)
+            .args(server_args(params, &self.args));
+
+        command
+    }
+}
+
+impl Launcher for GradleLauncher {
+    fn command(&self, params: &LaunchParams) -> Command {
+        let mut command = if params.dir.join("gradlew").exists() {
+            Command::new("./gradlew")
+        } else {
+            Command::new("gradle")
+        };
+
+        let args: Vec<_> = server_args(params, &self.args).iter().map(|a| quote_gradle_arg(a)).collect();
+        command.arg(&self.task)
+            .arg("--no-daemon")
+            .arg("--console=plain")
+            .arg(format!("--args={}", args.join(" ")));
+
+        command
+    }
+}
+
+impl Launcher for CommandLauncher {
+    fn command(&self, params: &LaunchParams) -> Command {
+        let mut command = Command::new("sh");
+        command.arg("-c")
+            .arg(&self.command)
+            .env("ENIGMA_JAR", params.jar)
+            .env("ENIGMA_MAPPINGS", params.mappings)
+            .env("ENIGMA_PORT", params.port.to_string())
+            .env("ENIGMA_PASSWORD", params.password.unwrap_or_default());
+
+        command
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use super::*;
+
+    fn params(dir: &Path) -> LaunchParams<'_> {
+        LaunchParams {
+            dir,
+            jar: "build/server.jar",
+            mappings: "mappings/",
+            port: 34712,
+            password: Some("secret"),
+        }
+    }
+
+    fn args(command: &Command) -> Vec<&str> {
+        command.get_args().map(|a| a.to_str().expect("Invalid argument")).collect()
+    }
+
+    #[test]
+    fn test_java() {
+        let launcher = JavaLauncher {
+            classpath: "enigma.jar".to_string(),
+            main_class: "Main".to_string(),
+            args: " -log  file ".to_string(),
+        };
+        let command = launcher.command(&params(Path::new(".")));
+
+        assert_eq!(OsStr::new("java"), command.get_program());
+        assert_eq!(vec!["-cp", "enigma.jar", "Main", "-jar", "build/server.jar", "-mappings", "mappings/", "-port", "34712",
+                        "-password", "secret", "-log", "file"], args(&command));
+    }
+
+    #[test]
+    fn test_gradle() {
+        let launcher = GradleLauncher {
+            task: "enigma".to_string(),
+            args: "".to_string(),
+        };
+        let params = LaunchParams { mappings: "my mappings/", password: None, ..params(Path::new("/nonexistent")) };
+        let command = launcher.command(&params);
+
+        assert_eq!(OsStr::new("gradle"), command.get_program());
+        assert_eq!(vec!["enigma", "--no-daemon", "--console=plain",
+                        "--args=-jar build/server.jar -mappings \"my mappings/\" -port 34712"], args(&command));
+    }
+
+    #[test]
+    fn test_check_password() {
+        assert!(check_password(LauncherKind::Gradle, "it's \"quoted\"").is_err());
+        assert!(check_password(LauncherKind::Gradle, "it's").is_ok());
+        assert!(check_password(LauncherKind::Java, "it's \"quoted\"").is_ok());
+        assert_eq!("'say \"hi\"'", quote_gradle_arg("say \"hi\""));
+    }
+
+    #[test]
+    fn test_command() {
+        let launcher = CommandLauncher {
+            command: "./start.sh".to_string(),
+        };
+        let command = launcher.command(&params(Path::new(".")));
+
+        assert_eq!(vec!["-c", "./start.sh"], args(&command));
+        let port = command.get_envs().find(|(key, _)| *key == "ENIGMA_PORT").and_then(|(_, value)| value);
+        assert_eq!(Some(OsStr::new("34712")), port);
+    }
+}
@@ -0,0 +1,197 @@
+use std::cmp::Reverse;
+use std::error::Error;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::result::Result as StdResult;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::util;
+use crate::util::throw;
+
+const FILE: &str = "data/logins.toml";
+const TOKEN_LENGTH: usize = 48;
+/// Logins expire after this long without any request
+const IDLE_TIMEOUT_HOURS: i64 = 48;
+/// Logins expire after this long, even if they're still being used
+const MAX_AGE_DAYS: i64 = 30;
+/// The last seen date is only written to disk when it changes by more than this
+const LAST_SEEN_PRECISION_MINUTES: i64 = 5;
+
+type Result<T> = StdResult<T, Box<dyn Error>>;
+
+/// A server-side record of a logged in browser, identified by the token in its `session` cookie
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Login {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    /// SHA3-256 hash of the cookie token
+    token_hash: String,
+    pub created: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct LoginStore {
+    #[serde(default)]
+    logins: Vec<Login>,
+}
+
+impl Login {
+    pub fn expires(&self) -> DateTime<Utc> {
+        (self.last_seen + Duration::hours(IDLE_TIMEOUT_HOURS))
+            .min(self.created + Duration::days(MAX_AGE_DAYS))
+    }
+
+    fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires() <= now
+    }
+}
+
+impl LoginStore {
+    fn deserialize<P: AsRef<Path>>(path: P) -> Result<LoginStore> {
+        let toml_str = fs::read_to_string(path)?;
+        let store = toml::from_str(toml_str.as_str())?;
+        Ok(store)
+    }
+
+    fn serialize<P: AsRef<Path>>(path: P, store: &LoginStore) -> Result<()> {
+        let toml_str = toml::to_string_pretty(store)?;
+        fs::create_dir_all("data/")?;
+        fs::write(path, toml_str)?;
+
+        Ok(())
+    }
+
+    fn write(&self) -> Result<()> {
+        Self::serialize(FILE, self)
+    }
+
+    fn purge_expired(&mut self) -> bool {
+        let now = Utc::now();
+        let len = self.logins.len();
+        self.logins.retain(|l| !l.is_expired(now));
+        self.logins.len() != len
+    }
+
+    /// Record a new login, returning the token for its cookie
+    pub fn create(&mut self, user_id: Uuid, ip: Option<IpAddr>, user_agent: Option<String>) -> Result<String> {
+        self.purge_expired();
+
+        let token = util::random_string(TOKEN_LENGTH);
+        let now = Utc::now();
+        self.logins.push(Login {
+            id: Uuid::new_v4(),
+            user_id,
+            token_hash: util::sha3_256(&token),
+            created: now,
+            last_seen: now,
+            ip,
+            user_agent,
+        });
+        self.write()?;
+
+        Ok(token)
+    }
+
+    /// Find the login for a cookie token, if it hasn't expired, and mark it as seen
+    pub fn authenticate(&mut self, token: &str) -> Option<&Login> {
+        let now = Utc::now();
+        let token_hash = util::sha3_256(token);
+        let index = self.logins.iter().position(|l| l.token_hash == token_hash && !l.is_expired(now))?;
+
+        let login = &mut self.logins[index];
+        let outdated = now - login.last_seen > Duration::minutes(LAST_SEEN_PRECISION_MINUTES);
+        login.last_seen = now;
+        if outdated {
+            if let Err(e) = self.write() {
+                eprintln!("Failed to write the logins: {e}");
+            }
+        }
+
+        self.logins.get(index)
+    }
+
+    /// Active logins of a user, newest first
+    pub fn for_user(&self, user_id: Uuid) -> Vec<&Login> {
+        let now = Utc::now();
+        let mut logins: Vec<_> = self.logins.iter()
+            .filter(|l| l.user_id == user_id && !l.is_expired(now))
+            .collect();
+        logins.sort_by_key(|l| Reverse(l.last_seen));
+        logins
+    }
+
+    pub fn revoke(&mut self, user_id: Uuid, id: Uuid) -> Result<()> {
+        let len = self.logins.len();
+        self.logins.retain(|l| !(l.user_id == user_id && l.id == id));
+        if self.logins.len() == len {
+            throw!("Login not found");
+        }
+
+        self.write()
+    }
+
+    pub fn revoke_token(&mut self, token: &str) -> Result<()> {
+        let token_hash = util::sha3_256(token);
+        self.logins.retain(|l| l.token_hash != token_hash);
+
+        self.write()
+    }
+
+    /// Revoke every login of a user, i.e. after changing their credentials
+    pub fn revoke_all(&mut self, user_id: Uuid) -> Result<()> {
+        self.logins.retain(|l| l.user_id != user_id);
+
+        self.write()
+    }
+}
+
+pub fn load_logins() -> Result<LoginStore> {
+    let path = Path::new(FILE);
+    let mut store = if path.exists() {
+        LoginStore::deserialize(path)?
+    } else {
+        LoginStore::default()
+    };
+
+    if store.purge_expired() {
+        store.write()?;
+    }
+
+    Ok(store)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn login(created: DateTime<Utc>, last_seen: DateTime<Utc>) -> Login {
+        Login {
+            id: Uuid::new_v4(),
+            user_id: Uuid::new_v4(),
+            token_hash: String::new(),
+            created,
+            last_seen,
+            ip: None,
+            user_agent: None,
+        }
+    }
+
+    #[test]
+    fn test_expiry() {
+        let now = Utc::now();
+
+        assert!(!login(now, now).is_expired(now));
+        assert!(login(now, now).is_expired(now + Duration::hours(IDLE_TIMEOUT_HOURS)), "Idle login not expired");
+
+        let created = now - Duration::days(MAX_AGE_DAYS) + Duration::hours(1);
+        assert!(!login(created, now).is_expired(now));
+        assert!(login(created, now).is_expired(now + Duration::hours(1)), "Old login not expired");
+    }
+}
@@ -0,0 +1,112 @@
+use std::io::{Result as IoResult, SeekFrom};
+use std::path::Path;
+
+use rocket::tokio::fs::File;
+use rocket::tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+const READ_BUFFER_SIZE: usize = 8192;
+
+/// Follows a log file as it grows, like `tail -f`, yielding complete lines only
+pub struct LogTail {
+    file: File,
+    /// Bytes read after the last complete line
+    pending: Vec<u8>,
+}
+
+impl LogTail {
+    /// Open a log file, starting with (up to) the last `backfill` bytes of it
+    pub async fn open<P: AsRef<Path>>(path: P, backfill: u64) -> IoResult<LogTail> {
+        let mut file = File::open(path).await?;
+        let len = file.metadata().await?.len();
+        // Start one byte early, to tell whether the first line is complete
+        let start = len.saturating_sub(backfill + 1);
+        file.seek(SeekFrom::Start(start)).await?;
+
+        let mut tail = LogTail {
+            file,
+            pending: vec![],
+        };
+
+        // Skip the line cut in half by the backfill limit
+        if start > 0 {
+            tail.read().await?;
+            let cut = tail.pending.iter().position(|b| *b == b'\n').map_or(tail.pending.len(), |i| i + 1);
+            tail.pending.drain(..cut);
+        }
+
+        Ok(tail)
+    }
+
+    async fn read(&mut self) -> IoResult<usize> {
+        let mut buf = [0u8; READ_BUFFER_SIZE];
+        let mut total = 0;
+
+        loop {
+            let count = self.file.read(&mut buf).await?;
+            if count == 0 {
+                return Ok(total);
+            }
+
+            self.pending.extend_from_slice(&buf[..count]);
+            total += count;
+        }
+    }
+
+    /// Read the lines written since the last call, without the trailing line break
+    pub async fn next_lines(&mut self) -> IoResult<Option<String>> {
+        self.read().await?;
+
+        let Some(end) = self.pending.iter().rposition(|b| *b == b'\n') else {
+            return Ok(None);
+        };
+        let lines: Vec<u8> = self.pending.drain(..=end).collect();
+
+        Ok(Some(String::from_utf8_lossy(&lines[..end]).into_owned()))
+    }
+
+    /// The last line, if it doesn't end with a line break, i.e. once the process has exited
+    pub fn remainder(&mut self) -> Option<String> {
+        if self.pending.is_empty() {
+            return None;
+        }
+
+        let line = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending.clear();
+        Some(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    use rocket::tokio;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_tail() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempdir()?;
+        let path = dir.path().join("stdout.log");
+        fs::write(&path, "first\nsecond\nthi")?;
+
+        let mut tail = LogTail::open(&path, 1024).await?;
+        assert_eq!(Some("first\nsecond".to_string()), tail.next_lines().await?);
+        assert_eq!(None, tail.next_lines().await?, "Incomplete line returned");
+
+        let mut file = OpenOptions::new().append(true).open(&path)?;
+        write!(file, "rd\nfourth")?;
+        assert_eq!(Some("third".to_string()), tail.next_lines().await?);
+        assert_eq!(Some("fourth".to_string()), tail.remainder());
+        assert_eq!(None, tail.remainder());
+
+        // Only the complete lines within the backfill are returned
+        let mut tail = LogTail::open(&path, 12).await?;
+        assert_eq!(Some("third".to_string()), tail.next_lines().await?);
+
+        Ok(())
+    }
+}
@@ -1,32 +1,81 @@
 #[macro_use] extern crate rocket;
 
+use std::sync::Arc;
+
 use rocket::fairing::AdHoc;
 use rocket::State;
 use rocket::tokio::sync::Mutex;
 use rocket_dyn_templates::Template;
 
-use crate::sessions::Session;
+use crate::logins::LoginStore;
+use crate::sessions::{Session, SessionLimits};
+use crate::throttle::LoginThrottle;
+use crate::users::UserStore;
 
+mod audit;
+mod auth;
+mod csrf;
+mod launcher;
+mod logins;
+mod logs;
+mod markdown;
+mod password;
+mod process;
 mod routes;
 mod settings;
 mod repo;
 mod sessions;
+mod tasks;
+mod throttle;
+mod totp;
+mod users;
 mod util;
 
-type SessionList = Mutex<Vec<Session>>;
+type SessionList = Arc<Mutex<Vec<Session>>>;
 type SessionsState<'r> = &'r State<SessionList>;
+// When several of these are held at once, they're always locked in this order: login attempts, users, logins
+type Users = Mutex<UserStore>;
+type UsersState<'r> = &'r State<Users>;
+type Logins = Mutex<LoginStore>;
+type LoginsState<'r> = &'r State<Logins>;
+type LoginAttempts = Mutex<LoginThrottle>;
+type LoginAttemptsState<'r> = &'r State<LoginAttempts>;
 
 #[launch]
 fn rocket() -> _ {
-    rocket::build()
+    rocket::custom(auth::figment())
         .mount("/", routes::routes())
         .attach(Template::fairing())
+        .attach(csrf::CsrfFairing)
+        .manage(LoginAttempts::default())
         .attach(AdHoc::try_on_ignite("Sessions", |rocket| async {
-            let sessions = match sessions::load_sessions() {
+            let default_limits = match settings::read_settings().await {
+                Ok(s) => SessionLimits::from_settings(&s),
+                Err(e) => panic!("Failed to read the settings: {e}"),
+            };
+            let sessions = match sessions::load_sessions(default_limits).await {
                 Ok(s) => s,
                 Err(e) => panic!("Failed to load the sessions: {e}"),
             };
 
-            Ok(rocket.manage(SessionList::new(sessions)))
+            Ok(rocket.manage(SessionList::new(Mutex::new(sessions))))
+        }))
+        .attach(tasks::auto_save())
+        .attach(tasks::session_limits())
+        .attach(AdHoc::try_on_ignite("Users", |rocket| async {
+            let users = match users::load_users() {
+                Ok(u) => u,
+                Err(e) => panic!("Failed to load the users: {e}"),
+            };
+
+            Ok(rocket.manage(Users::new(users)))
+        }))
+        .attach(AdHoc::try_on_ignite("Logins", |rocket| async {
+            let logins = match logins::load_logins() {
+                Ok(l) => l,
+                Err(e) => panic!("Failed to load the logins: {e}"),
+            };
+
+            Ok(rocket.manage(Logins::new(logins)))
         }))
 }
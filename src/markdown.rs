@@ -0,0 +1,59 @@
+use pulldown_cmark::{CowStr, Event, html, Options, Parser, Tag};
+
+/// URL schemes allowed in links and images, anything else is dropped
+const ALLOWED_SCHEMES: [&str; 3] = ["http", "https", "mailto"];
+
+/// Render user-written markdown as html, safe to embed in a page
+///
+/// Raw html is escaped instead of passed through, and links to other schemes than [`ALLOWED_SCHEMES`] are emptied
+pub fn to_html(markdown: &str) -> String {
+    let parser = Parser::new_ext(markdown, Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TABLES)
+        .map(|event| match event {
+            Event::Html(html) | Event::InlineHtml(html) => Event::Text(html),
+            Event::Start(Tag::Link { link_type, dest_url, title, id }) => Event::Start(Tag::Link {
+                link_type,
+                dest_url: safe_url(dest_url),
+                title,
+                id,
+            }),
+            Event::Start(Tag::Image { link_type, dest_url, title, id }) => Event::Start(Tag::Image {
+                link_type,
+                dest_url: safe_url(dest_url),
+                title,
+                id,
+            }),
+            event => event,
+        });
+
+    let mut output = String::new();
+    html::push_html(&mut output, parser);
+    output
+}
+
+fn safe_url(url: CowStr) -> CowStr {
+    // Relative urls have no scheme, or a colon only after a path, query or fragment
+    let scheme = url.split_once(':')
+        .map(|(scheme, _)| scheme)
+        .filter(|scheme| !scheme.contains(['/', '?', '#']));
+
+    match scheme {
+        Some(scheme) if !ALLOWED_SCHEMES.contains(&scheme.to_ascii_lowercase().as_str()) => CowStr::Borrowed(""),
+        _ => url,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_html() {
+        assert_eq!("<p>Map the <em>worldgen</em> package</p>\n", to_html("Map the *worldgen* package"));
+        assert_eq!("&lt;script&gt;alert(1)&lt;/script&gt;", to_html("<script>alert(1)</script>"));
+        assert_eq!("<p>A &lt;b&gt;bold&lt;/b&gt; move</p>\n", to_html("A <b>bold</b> move"));
+        assert_eq!("<p><a href=\"https://example.com\">docs</a></p>\n", to_html("[docs](https://example.com)"));
+        assert_eq!("<p><a href=\"/sessions\">sessions</a></p>\n", to_html("[sessions](/sessions)"));
+        assert_eq!("<p><a href=\"\">click</a></p>\n", to_html("[click](javascript:alert(1))"));
+        assert_eq!("<p><a href=\"\">click</a></p>\n", to_html("[click](JavaScript:alert(1))"));
+    }
+}
@@ -0,0 +1,168 @@
+use std::error::Error;
+use std::sync::OnceLock;
+
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::{Error as HashError, SaltString};
+use rand::rngs::OsRng;
+use rocket::tokio::task;
+
+use crate::util;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verification {
+    Invalid,
+    Valid,
+    /// The password matched, but the stored hash is outdated and should be replaced with a new one
+    NeedsRehash,
+}
+
+/// Hash a password with Argon2id, returning it in the PHC string format
+pub fn hash(password: &str) -> Result<String, HashError> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default().hash_password(password.as_bytes(), &salt)?;
+    Ok(hash.to_string())
+}
+
+/// Hash a new password for an account, which can't be empty
+///
+/// Runs on a blocking thread, since hashing is slow on purpose
+pub async fn hash_new(password: &str) -> Result<String, Box<dyn Error>> {
+    if password.is_empty() {
+        return Err("The password can't be empty".into());
+    }
+
+    let password = password.to_string();
+    Ok(task::spawn_blocking(move || hash(&password)).await??)
+}
+
+/// Legacy hashes are unsalted SHA3-256 hex digests
+fn is_legacy(hash: &str) -> bool {
+    hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+pub fn verify(password: &str, hash: &str) -> Verification {
+    if is_legacy(hash) {
+        return if util::constant_time_eq(&util::sha3_256(password), &hash.to_lowercase()) {
+            Verification::NeedsRehash
+        } else {
+            Verification::Invalid
+        };
+    }
+
+    let parsed = match PasswordHash::new(hash) {
+        Ok(h) => h,
+        Err(e) => {
+            eprintln!("Invalid password hash: {e}");
+            return Verification::Invalid;
+        }
+    };
+
+    if Argon2::default().verify_password(password.as_bytes(), &parsed).is_err() {
+        return Verification::Invalid;
+    }
+
+    let current = Params::default();
+    let outdated = parsed.algorithm != Algorithm::Argon2id.ident()
+        || Params::try_from(&parsed).map_or(true, |p| {
+            p.m_cost() != current.m_cost() || p.t_cost() != current.t_cost() || p.p_cost() != current.p_cost()
+        });
+    if outdated {
+        Verification::NeedsRehash
+    } else {
+        Verification::Valid
+    }
+}
+
+/// Verify a password against the hash of an account, or against a dummy hash if there's no such account,
+/// so unknown users take as long to reject as wrong passwords
+pub fn verify_account(password: &str, hash: Option<&str>) -> Verification {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+
+    match hash {
+        Some(hash) => verify(password, hash),
+        None => {
+            let dummy = DUMMY_HASH.get_or_init(|| self::hash(&util::random_string(32)).expect("Failed to hash a dummy password"));
+            verify(password, dummy);
+            Verification::Invalid
+        }
+    }
+}
+
+/// [`verify_account`] on a blocking thread, since verifying is slow on purpose
+pub async fn verify_account_async(password: &str, hash: Option<String>) -> Verification {
+    let password = password.to_string();
+    task::spawn_blocking(move || verify_account(&password, hash.as_deref())).await
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to verify a password: {e}");
+            Verification::Invalid
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash() -> Result<(), HashError> {
+        let hash1 = hash("password")?;
+        let hash2 = hash("password")?;
+
+        assert!(hash1.starts_with("$argon2id$"), "Not an Argon2id PHC string");
+        assert_ne!(hash1, hash2, "Hashes aren't salted");
+
+        assert_eq!(Verification::Valid, verify("password", &hash1));
+        assert_eq!(Verification::Invalid, verify("Password", &hash1));
+        assert_eq!(Verification::Invalid, verify("", &hash1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_legacy_hash() {
+        let legacy = util::sha3_256("password");
+
+        assert_eq!(Verification::NeedsRehash, verify("password", &legacy));
+        assert_eq!(Verification::NeedsRehash, verify("password", &legacy.to_uppercase()));
+        assert_eq!(Verification::Invalid, verify("wrong", &legacy));
+    }
+
+    #[test]
+    fn test_outdated_params() -> Result<(), HashError> {
+        let params = Params::new(8 * 1024, 1, 1, None)?;
+        let argon2 = Argon2::new(Algorithm::Argon2id, Default::default(), params);
+        let salt = SaltString::generate(&mut OsRng);
+        let weak = argon2.hash_password("password".as_bytes(), &salt)?.to_string();
+
+        assert_eq!(Verification::NeedsRehash, verify("password", &weak));
+        assert_eq!(Verification::Invalid, verify("wrong", &weak));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_account() -> Result<(), HashError> {
+        let hash = hash("password")?;
+
+        assert_eq!(Verification::Valid, verify_account("password", Some(&hash)));
+        assert_eq!(Verification::Invalid, verify_account("password", None));
+
+        Ok(())
+    }
+
+    #[rocket::async_test]
+    async fn test_async() -> Result<(), Box<dyn Error>> {
+        let hash = hash_new("password").await?;
+
+        assert_eq!(Verification::Valid, verify_account_async("password", Some(hash.clone())).await);
+        assert_eq!(Verification::Invalid, verify_account_async("wrong", Some(hash)).await);
+        assert!(hash_new("").await.is_err(), "Hashed an empty password");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_hash() {
+        assert_eq!(Verification::Invalid, verify("password", ""));
+        assert_eq!(Verification::Invalid, verify("password", "not a hash"));
+    }
+}
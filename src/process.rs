@@ -0,0 +1,228 @@
+use std::fs;
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+use std::path::PathBuf;
+use std::process::{Child, ExitStatus};
+use std::time::{Duration, Instant};
+
+use libc::pid_t;
+use rocket::tokio::time::sleep;
+use serde::{Deserialize, Serialize};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Connection states in `/proc/net/tcp`
+const TCP_ESTABLISHED: &str = "01";
+const TCP_LISTEN: &str = "0A";
+/// How long to wait for a process to die after SIGKILL
+const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Identifies a process beyond its pid, which can be reused once it exits
+///
+/// Both stay the same when the process executes another program, like Gradle or `sh` starting `java`
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProcessInfo {
+    /// In clock ticks since boot
+    pub start_time: u64,
+    /// Missing for processes recorded by older versions
+    #[serde(default)]
+    pub pgid: Option<u32>,
+}
+
+impl ProcessInfo {
+    /// Read the info of a running process from `/proc`, `None` if it doesn't exist
+    pub fn read(pid: u32) -> IoResult<Option<ProcessInfo>> {
+        let stat = match fs::read_to_string(proc_dir(pid).join("stat")) {
+            Ok(stat) => stat,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        // The executable name comes first, in parentheses, and may contain spaces itself
+        let fields: Vec<_> = stat.rsplit_once(')')
+            .map(|(_, fields)| fields.split_whitespace().collect())
+            .unwrap_or_default();
+        let field = |index: usize| fields.get(index)
+            .and_then(|field| field.parse().ok())
+            .ok_or_else(|| IoError::other(format!("Invalid /proc stat for process {pid}")));
+
+        Ok(Some(ProcessInfo {
+            start_time: field(19)?,
+            pgid: Some(field(2)? as u32),
+        }))
+    }
+
+    /// Whether `current` is the info of this same process, read again later
+    pub fn matches(&self, current: &ProcessInfo) -> bool {
+        self.start_time == current.start_time && self.pgid.is_none_or(|pgid| current.pgid == Some(pgid))
+    }
+}
+
+/// Count the established TCP connections to a local port, over IPv4 and IPv6
+pub fn count_connections(port: u16) -> IoResult<usize> {
+    count_sockets(port, TCP_ESTABLISHED)
+}
+
+/// Whether any process listens on a local TCP port, over IPv4 or IPv6
+pub fn is_listening(port: u16) -> IoResult<bool> {
+    Ok(count_sockets(port, TCP_LISTEN)? > 0)
+}
+
+fn count_sockets(port: u16, state: &str) -> IoResult<usize> {
+    let mut count = 0;
+    for table in ["/proc/net/tcp", "/proc/net/tcp6"] {
+        match fs::read_to_string(table) {
+            Ok(table) => count += count_in_table(&table, port, state),
+            // IPv6 may be disabled
+            Err(e) if e.kind() == ErrorKind::NotFound => {},
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(count)
+}
+
+fn count_in_table(table: &str, port: u16, state: &str) -> usize {
+    table.lines()
+        .skip(1) // Header
+        .filter(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let local_port = fields.get(1)
+                .and_then(|address| address.rsplit_once(':'))
+                .and_then(|(_, port)| u16::from_str_radix(port, 16).ok());
+            local_port == Some(port) && fields.get(3) == Some(&state)
+        })
+        .count()
+}
+
+fn proc_dir(pid: u32) -> PathBuf {
+    PathBuf::from("/proc").join(pid.to_string())
+}
+
+pub enum State {
+    Running,
+    /// The exit status is only known for children of this process
+    Exited(Option<ExitStatus>),
+}
+
+/// Check whether a process is still running, reaping it if it's a child that exited
+pub fn poll(pid: u32, child: Option<&mut Child>) -> IoResult<State> {
+    if let Some(child) = child {
+        return Ok(match child.try_wait()? {
+            Some(status) => State::Exited(Some(status)),
+            None => State::Running,
+        });
+    }
+
+    // Started before a restart, it's been reparented so only its existence can be checked
+    if unsafe { libc::kill(pid as pid_t, 0) } == 0 {
+        return Ok(State::Running);
+    }
+
+    let e = IoError::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::ESRCH) => Ok(State::Exited(None)),
+        Some(libc::EPERM) => Ok(State::Running),
+        _ => Err(e),
+    }
+}
+
+/// Send a signal to the process group led by `pid`, returning whether it exists
+fn signal_group(pid: u32, signal: i32) -> IoResult<bool> {
+    check_signal(unsafe { libc::killpg(pid as pid_t, signal) })
+}
+
+fn signal_process(pid: u32, signal: i32) -> IoResult<bool> {
+    check_signal(unsafe { libc::kill(pid as pid_t, signal) })
+}
+
+fn check_signal(result: i32) -> IoResult<bool> {
+    if result == 0 {
+        return Ok(true);
+    }
+
+    let e = IoError::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::ESRCH) => Ok(false),
+        _ => Err(e),
+    }
+}
+
+async fn wait(pid: u32, child: &mut Option<&mut Child>, timeout: Duration) -> IoResult<State> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        let state = poll(pid, child.as_deref_mut())?;
+        if matches!(state, State::Exited(_)) || Instant::now() >= deadline {
+            return Ok(state);
+        }
+
+        sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Stop a process and its process group with SIGTERM, then SIGKILL if it's still running after `grace_period`
+pub async fn terminate(pid: u32, mut child: Option<&mut Child>, grace_period: Duration) -> IoResult<Option<ExitStatus>> {
+    // Processes started without their own group only get the signal themselves
+    if !signal_group(pid, libc::SIGTERM)? {
+        signal_process(pid, libc::SIGTERM)?;
+    }
+
+    let status = match wait(pid, &mut child, grace_period).await? {
+        State::Exited(status) => status,
+        State::Running => {
+            eprintln!("Process {pid} is still running after {} seconds, killing it", grace_period.as_secs());
+            if !signal_group(pid, libc::SIGKILL)? {
+                signal_process(pid, libc::SIGKILL)?;
+            }
+
+            match wait(pid, &mut child, KILL_TIMEOUT).await? {
+                State::Exited(status) => status,
+                State::Running => return Err(IoError::other(format!("Process {pid} didn't exit after SIGKILL"))),
+            }
+        }
+    };
+
+    // Helper processes shouldn't outlive the main one, only the group is signalled as the pid may have been reused
+    signal_group(pid, libc::SIGKILL)?;
+
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_process_info() -> Result<(), Box<dyn std::error::Error>> {
+        let info = ProcessInfo::read(std::process::id())?.expect("Missing info of the current process");
+        assert_eq!(Some(unsafe { libc::getpgrp() } as u32), info.pgid);
+        assert!(info.matches(&ProcessInfo::read(std::process::id())?.expect("Current process is gone")));
+
+        // Recorded by older versions
+        let older: ProcessInfo = toml::from_str(&format!("start_time = {}\ncmdline_sha3 = \"abc\"", info.start_time))?;
+        assert!(older.matches(&info));
+        assert!(!ProcessInfo { start_time: info.start_time + 1, ..older }.matches(&info));
+        assert!(!ProcessInfo { start_time: info.start_time, pgid: info.pgid.map(|pgid| pgid + 1) }.matches(&info));
+
+        assert_eq!(None, ProcessInfo::read(u32::MAX)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_count_in_table() {
+        let table = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 00000000:87B8 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 1 1 0000000000000000 100 0 0 10 0
+   1: 0100007F:87B8 0100007F:D2F0 01 00000000:00000000 00:00000000 00000000  1000        0 2 1 0000000000000000 20 4 30 10 -1
+   2: 0100007F:D2F0 0100007F:87B8 01 00000000:00000000 00:00000000 00000000  1000        0 3 1 0000000000000000 20 4 30 10 -1
+   3: 0100007F:87B8 0100007F:D2F2 06 00000000:00000000 00:00000000 00000000  1000        0 4 1 0000000000000000 20 4 30 10 -1
+   4: 0100007F:87B9 0100007F:D2F4 01 00000000:00000000 00:00000000 00000000  1000        0 5 1 0000000000000000 20 4 30 10 -1
+";
+
+        // Listening, outgoing, closing and other ports aren't counted
+        assert_eq!(1, count_in_table(table, 34744, TCP_ESTABLISHED));
+        assert_eq!(0, count_in_table(table, 34746, TCP_ESTABLISHED));
+
+        assert_eq!(1, count_in_table(table, 34744, TCP_LISTEN));
+        assert_eq!(0, count_in_table(table, 34745, TCP_LISTEN));
+    }
+}
@@ -5,20 +5,31 @@ use std::path::{Path, PathBuf};
 use std::process::{Command, ExitStatus};
 use std::str::from_utf8;
 
-use git2::{AnnotatedCommit, BranchType, DiffDelta, DiffFormat, DiffHunk, DiffLine, DiffLineType, FetchOptions, IndexAddOption, ObjectType, Oid, Repository, ResetType, StatusOptions};
+use chrono::{DateTime, Utc};
+use git2::{AnnotatedCommit, ApplyLocation, ApplyOptions, BranchType, Cred, CredentialType, Delta, Diff, DiffDelta, DiffFormat, DiffHunk, DiffLine, DiffLineType, DiffOptions, ErrorCode, FetchOptions, IndexAddOption, ObjectType, Oid, PushOptions, RemoteCallbacks, Repository, ResetType, Signature, StatusOptions, WorktreeAddOptions, WorktreePruneOptions};
 use git2::build::{CheckoutBuilder, RepoBuilder};
 
-use crate::settings::read_settings;
+use crate::settings::{read_settings, RemoteSettings};
 use crate::util::throw;
 
 pub const DIR: &str = "data/repo";
+pub const WORKTREES_DIR: &str = "data/worktrees";
+/// Prefix of the branches created for the session worktrees
+const WORKTREE_BRANCH_PREFIX: &str = "colab/";
+/// Committer used when the repository has no `user.name` and `user.email` configured
+const DEFAULT_SIGNATURE_NAME: &str = "Enigma CoLab";
+const DEFAULT_SIGNATURE_EMAIL: &str = "colab@localhost";
+/// Written by `git format-patch` in place of the commit id when there's no commit
+const ZERO_COMMIT: &str = "0000000000000000000000000000000000000000";
 
 type Git2Result<T> = Result<T, git2::Error>;
+/// The path of a file in the repository, and its contents
+pub type FileContents = (String, Vec<u8>);
 
-pub fn run_command(command: &String) -> IoResult<Option<ExitStatus>> {
+pub fn run_command<P: AsRef<Path>>(command: &String, dir: P) -> IoResult<Option<ExitStatus>> {
     Ok(if !command.is_empty() {
         Some(Command::new("sh")
-            .current_dir(DIR)
+            .current_dir(dir)
             .arg("-c")
             .arg(command)
             .status()?)
@@ -31,6 +42,10 @@ fn open_repo() -> Git2Result<Repository> {
     Repository::open(DIR)
 }
 
+pub fn open_worktree<P: AsRef<Path>>(path: P) -> Git2Result<Repository> {
+    Repository::open(path)
+}
+
 pub async fn clone() -> Result<(String, String), Box<dyn Error>> {
     let settings = read_settings().await?;
     let branch = settings.repo.branch;
@@ -39,7 +54,7 @@ pub async fn clone() -> Result<(String, String), Box<dyn Error>> {
     let repo = clone_repo(url.as_str(), Some(branch.as_str()), Path::new(DIR))?;
 
     // TODO: Run on another thread
-    run_command(&settings.pull_cmd)?;
+    run_command(&settings.pull_cmd, DIR)?;
 
     let rev = repo.revparse_single("HEAD")?.id();
     Ok((branch, rev.to_string()))
@@ -63,17 +78,17 @@ pub async fn list_local_branches() -> Git2Result<Vec<String>> {
     Ok(result)
 }
 
+/// The branch checked out in the main repository, where finished sessions are merged
+pub fn current_branch() -> Git2Result<Option<String>> {
+    head_branch(&open_repo()?)
+}
+
 pub fn get_repo_head(repo: &Repository) -> Git2Result<String> {
     let direct_head = repo.head()?.resolve()?;
     let target = direct_head.target().unwrap_or(Oid::zero()); // Safe to unwrap, only None if the reference isn't direct
     Ok(target.to_string())
 }
 
-pub fn get_head() -> Git2Result<String> {
-    let repo = open_repo()?;
-    get_repo_head(&repo)
-}
-
 pub fn clone_repo<P: AsRef<Path>>(uri: &str, branch: Option<&str>, path: P) -> Git2Result<Repository> {
     let mut builder = RepoBuilder::new();
     if let Some(branch) = branch {
@@ -83,14 +98,60 @@ pub fn clone_repo<P: AsRef<Path>>(uri: &str, branch: Option<&str>, path: P) -> G
     builder.clone(uri, path.as_ref())
 }
 
-pub fn fetch() -> Git2Result<()> {
+/// The result of pushing a single ref
+#[derive(Debug, PartialEq, Eq)]
+pub struct PushUpdate {
+    pub refname: String,
+    /// Why the remote rejected the update, if it did
+    pub rejection: Option<String>,
+}
+
+/// Authenticate with the configured token or SSH key, falling back to the default credentials
+///
+/// Each kind of credential is only offered once, so bad credentials fail instead of being retried forever
+fn remote_callbacks(settings: &RemoteSettings) -> RemoteCallbacks<'_> {
+    let mut tried = CredentialType::empty();
+    let mut callbacks = RemoteCallbacks::new();
+
+    callbacks.credentials(move |_url, url_username, allowed| {
+        let username = match settings.username.as_str() {
+            "" => url_username.unwrap_or("git"),
+            username => username,
+        };
+
+        if allowed.contains(CredentialType::USERNAME) && !tried.contains(CredentialType::USERNAME) {
+            tried |= CredentialType::USERNAME;
+            Cred::username(username)
+        } else if allowed.contains(CredentialType::SSH_KEY) && !settings.ssh_key.is_empty()
+            && !tried.contains(CredentialType::SSH_KEY) {
+            tried |= CredentialType::SSH_KEY;
+            let passphrase = Some(settings.ssh_passphrase.as_str()).filter(|p| !p.is_empty());
+            Cred::ssh_key(username, None, Path::new(&settings.ssh_key), passphrase)
+        } else if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) && !settings.token.is_empty()
+            && !tried.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            tried |= CredentialType::USER_PASS_PLAINTEXT;
+            Cred::userpass_plaintext(username, &settings.token)
+        } else if allowed.contains(CredentialType::DEFAULT) && !tried.contains(CredentialType::DEFAULT) {
+            tried |= CredentialType::DEFAULT;
+            Cred::default()
+        } else {
+            Err(git2::Error::from_str("No valid credentials for the remote, check the remote settings"))
+        }
+    });
+
+    callbacks
+}
+
+pub async fn fetch() -> Result<(), Box<dyn Error>> {
+    let settings = read_settings().await?;
     let repo = open_repo()?;
-    fetch_repo(&repo)
+    Ok(fetch_repo(&repo, &settings.remote)?)
 }
 
 /// Based on libgit2's [example fetch.c](https://libgit2.org/libgit2/ex/v1.7.1/fetch.html)
-pub fn fetch_repo(repo: &Repository) -> Git2Result<()> {
+pub fn fetch_repo(repo: &Repository, settings: &RemoteSettings) -> Git2Result<()> {
     let mut options = FetchOptions::new(); // TODO: Progress message
+    options.remote_callbacks(remote_callbacks(settings));
     let remotes = repo.remotes()?;
     let mut remotes_iter = remotes.iter();
 
@@ -115,12 +176,12 @@ pub fn fetch_repo(repo: &Repository) -> Git2Result<()> {
 }
 
 pub async fn pull() -> Result<Result<String, String>, Box<dyn Error>> {
+    let settings = read_settings().await?;
     let repo = open_repo()?;
 
-    let result = pull_repo(&repo).map(|r| { r.map(|id| id.to_string()) })?;
+    let result = pull_repo(&repo, &settings.remote).map(|r| { r.map(|id| id.to_string()) })?;
     if result.is_ok() {
-        let settings = read_settings().await?;
-        run_command(&settings.pull_cmd)?;
+        run_command(&settings.pull_cmd, DIR)?;
     }
 
     Ok(result)
@@ -129,7 +190,7 @@ pub async fn pull() -> Result<Result<String, String>, Box<dyn Error>> {
 /// Based on libgit2's [example merge.c](https://libgit2.org/libgit2/ex/v1.7.1/merge.html)
 ///
 /// The successful (inner) result has either the new HEAD hash, or a message specifying why it wasn't updated
-pub fn pull_repo(repo: &Repository) -> Result<Result<Oid, String>, Box<dyn Error>> {
+pub fn pull_repo(repo: &Repository, settings: &RemoteSettings) -> Result<Result<Oid, String>, Box<dyn Error>> {
     let mut head_ref = repo.head()?;
 
     if let Some(current_branch) = head_ref.shorthand() {
@@ -141,7 +202,9 @@ pub fn pull_repo(repo: &Repository) -> Result<Result<Oid, String>, Box<dyn Error
         let remote_name = remote_name.as_str().unwrap_or("<unknown remote>");
         let mut remote = repo.find_remote(remote_name)?;
 
-        remote.fetch::<&str>(&[], None, None)?;
+        let mut options = FetchOptions::new();
+        options.remote_callbacks(remote_callbacks(settings));
+        remote.fetch::<&str>(&[], Some(&mut options), None)?;
 
         let remote_branch = branch.upstream()?;
         let merge_target = repo.reference_to_annotated_commit(remote_branch.get())?;
@@ -171,8 +234,80 @@ pub fn pull_repo(repo: &Repository) -> Result<Result<Oid, String>, Box<dyn Error
     throw!("Not currently on a branch")
 }
 
-fn resolve_ref<'r>(repo: &'r Repository, target_ref: &String) -> Git2Result<Option<AnnotatedCommit<'r>>> {
-    let resolved = repo.resolve_reference_from_short_name(target_ref.as_str());
+pub async fn push() -> Result<Vec<PushUpdate>, Box<dyn Error>> {
+    let settings = read_settings().await?;
+    let repo = open_repo()?;
+
+    push_repo(&repo, &settings.remote)
+}
+
+/// Push the checked out branch to the configured remote, returning the result for each ref
+///
+/// Equivalent to `git push <remote> <branch>`
+pub fn push_repo(repo: &Repository, settings: &RemoteSettings) -> Result<Vec<PushUpdate>, Box<dyn Error>> {
+    let head = repo.head()?;
+    if !head.is_branch() {
+        throw!("Not currently on a branch")
+    }
+    let branch_ref = head.name().ok_or("Branch ref has an invalid name")?;
+    let mut remote = repo.find_remote(&settings.push_remote)?;
+
+    let mut updates = vec![];
+    let mut callbacks = remote_callbacks(settings);
+    callbacks.push_update_reference(|refname, status| {
+        updates.push(PushUpdate {
+            refname: refname.to_string(),
+            rejection: status.map(str::to_string),
+        });
+        Ok(())
+    });
+
+    let mut options = PushOptions::new();
+    options.remote_callbacks(callbacks);
+    remote.push(&[format!("{branch_ref}:{branch_ref}")], Some(&mut options))?;
+    // Release the callbacks, which borrow `updates`
+    drop(options);
+
+    Ok(updates)
+}
+
+/// The short name of the branch checked out in the repository, `None` if HEAD is detached
+pub fn head_branch(repo: &Repository) -> Git2Result<Option<String>> {
+    let head = repo.head()?;
+    Ok(if head.is_branch() {
+        head.shorthand().map(str::to_string)
+    } else {
+        None
+    })
+}
+
+/// The local branch matching a ref a worktree was started from, `None` if it's a tag or a revision
+///
+/// Remote branches such as `origin/main` match the local branch of the same name, like in [`add_worktree`]
+pub fn ref_branch(repo: &Repository, target_ref: &str) -> Git2Result<Option<String>> {
+    if repo.find_reference(&format!("refs/tags/{target_ref}")).is_ok() {
+        return Ok(None);
+    }
+    if repo.find_branch(target_ref, BranchType::Local).is_ok() {
+        return Ok(Some(target_ref.to_string()));
+    }
+
+    for remote in repo.remotes()?.iter().flatten() {
+        if let Some(name) = target_ref.strip_prefix(&format!("{remote}/")) {
+            if repo.find_branch(target_ref, BranchType::Remote).is_ok() {
+                return Ok(Some(name.to_string()));
+            }
+        }
+        if repo.find_reference(&format!("refs/remotes/{remote}/{target_ref}")).is_ok() {
+            return Ok(Some(target_ref.to_string()));
+        }
+    }
+
+    Ok(None)
+}
+
+fn resolve_ref<'r>(repo: &'r Repository, target_ref: &str) -> Git2Result<Option<AnnotatedCommit<'r>>> {
+    let resolved = repo.resolve_reference_from_short_name(target_ref);
 
     if let Ok(resolved_ref) = resolved {
         let commit = repo.reference_to_annotated_commit(&resolved_ref)?;
@@ -188,32 +323,29 @@ fn resolve_ref<'r>(repo: &'r Repository, target_ref: &String) -> Git2Result<Opti
     Ok(None)
 }
 
-fn guess_ref<'r>(repo: &'r Repository, target_ref: &String) -> Git2Result<Option<AnnotatedCommit<'r>>> {
+fn guess_ref<'r>(repo: &'r Repository, target_ref: &str) -> Git2Result<Option<AnnotatedCommit<'r>>> {
     let remotes = repo.remotes()?;
 
     let mut error = None;
 
-    for remote in remotes.iter() {
-        if let Some(remote) = remote {
-            let refname = format!("refs/remotes/{}/{}", remote, target_ref);
+    for remote in remotes.iter().flatten() {
+        let refname = format!("refs/remotes/{}/{}", remote, target_ref);
 
-            let found_ref = match repo.find_reference(refname.as_str()) {
-                Ok(r) => r,
-                Err(e) => {
-                    error = Some(e);
-                    continue;
-                }
-            };
+        let found_ref = match repo.find_reference(refname.as_str()) {
+            Ok(r) => r,
+            Err(e) => {
+                error = Some(e);
+                continue;
+            }
+        };
 
-            let commit = repo.reference_to_annotated_commit(&found_ref)?;
-            return Ok(Some(commit))
-        }
+        let commit = repo.reference_to_annotated_commit(&found_ref)?;
+        return Ok(Some(commit))
     }
 
-    if error.is_some() {
-        Err(error.unwrap())
-    } else {
-        Ok(None)
+    match error {
+        Some(e) => Err(e),
+        None => Ok(None)
     }
 }
 
@@ -252,6 +384,49 @@ pub fn repo_checkout(repo: &Repository, target_ref: String) -> Result<Oid, Box<d
     Ok(target_oid)
 }
 
+fn worktree_branch(name: &str) -> String {
+    format!("{WORKTREE_BRANCH_PREFIX}{name}")
+}
+
+/// Create a new worktree in `path`, on a new `colab/<name>` branch starting at the given ref
+///
+/// Equivalent to `git worktree add -b colab/<name> <path> <target_ref>`
+pub fn add_worktree<P: AsRef<Path>>(repo: &Repository, name: &str, path: P, target_ref: &str) -> Result<Repository, Box<dyn Error>> {
+    let target = match resolve_ref(repo, target_ref)? {
+        Some(target) => target,
+        None => guess_ref(repo, target_ref)?.ok_or("Reference not found")?,
+    };
+    let commit = repo.find_commit(target.id())?;
+    let branch = repo.branch(&worktree_branch(name), &commit, false)?;
+
+    let path = path.as_ref();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut options = WorktreeAddOptions::new();
+    options.reference(Some(branch.get()));
+    let worktree = repo.worktree(name, path, Some(&options))?;
+
+    Ok(Repository::open_from_worktree(&worktree)?)
+}
+
+/// Delete a worktree created by [`add_worktree`], along with its working directory and branch
+///
+/// Equivalent to `git worktree remove --force <path> && git branch -D colab/<name>`
+pub fn remove_worktree(repo: &Repository, name: &str) -> Git2Result<()> {
+    let worktree = repo.find_worktree(name)?;
+    let mut options = WorktreePruneOptions::new();
+    options.valid(true).working_tree(true);
+    worktree.prune(Some(&mut options))?;
+
+    match repo.find_branch(&worktree_branch(name), BranchType::Local) {
+        Ok(mut branch) => branch.delete(),
+        Err(e) if e.code() == ErrorCode::NotFound => Ok(()),
+        Err(e) => Err(e)
+    }
+}
+
 pub async fn checkout() -> Result<String, Box<dyn Error>> {
     let settings = read_settings().await?;
     let repo = open_repo()?;
@@ -266,6 +441,15 @@ pub fn add(repo: &Repository, path: &[&str]) -> Git2Result<()> {
     index.write()
 }
 
+/// The configured `user.name` and `user.email`, or a default signature if there's none
+pub fn signature(repo: &Repository) -> Git2Result<Signature<'static>> {
+    match repo.signature() {
+        Ok(signature) => Ok(signature),
+        Err(e) if e.code() == ErrorCode::NotFound => Signature::now(DEFAULT_SIGNATURE_NAME, DEFAULT_SIGNATURE_EMAIL),
+        Err(e) => Err(e),
+    }
+}
+
 /// Create a new commit with the changes in the index and the given message
 ///
 /// Based on libgit2's [example commit.c](https://libgit2.org/libgit2/ex/v1.7.1/commit.html)
@@ -276,13 +460,13 @@ pub fn commit(repo: &Repository, message: &str) -> Git2Result<Oid> {
     index.write()?;
 
     let tree = repo.find_tree(tree_oid)?;
-    let signature = repo.signature()?;
+    let signature = signature(repo)?;
 
     repo.commit(Some("HEAD"), &signature, &signature, message, &tree, &[&parent])
 }
 
 fn diff_print(buf: &mut Vec<u8>) -> impl FnMut(DiffDelta<'_>, Option<DiffHunk<'_>>, DiffLine<'_>) -> bool + '_ {
-    return |_, _, line| {
+    |_, _, line| {
         let line_type = line.origin_value();
         let content = match from_utf8(line.content()) {
             Ok(c) => c,
@@ -304,7 +488,7 @@ fn diff_print(buf: &mut Vec<u8>) -> impl FnMut(DiffDelta<'_>, Option<DiffHunk<'_
         }
 
         true
-    };
+    }
 }
 
 /// Generate a patch diff of the changes in the index, and return its bytes
@@ -321,9 +505,43 @@ pub fn diff_bytes(repo: &Repository) -> Git2Result<Vec<u8>> {
     Ok(buf)
 }
 
-pub async fn create_patch() -> Result<Vec<u8>, Box<dyn Error>> {
+/// Generate a patch diff of the changes to the given paths in the working tree, including untracked files,
+/// and return its bytes
+///
+/// Same as staging the paths and calling [`diff_bytes`], but the index isn't touched
+pub fn diff_working_tree_bytes(repo: &Repository, path: &[&str]) -> Git2Result<Vec<u8>> {
+    let head = repo.revparse_single("HEAD")?;
+    let head_tree = head.peel_to_tree()?;
+
+    let mut options = DiffOptions::new();
+    options.include_untracked(true)
+        .recurse_untracked_dirs(true)
+        .show_untracked_content(true);
+    for p in path {
+        options.pathspec(p);
+    }
+
+    let mut buf = Vec::new();
+    let diff = repo.diff_tree_to_workdir_with_index(Some(&head_tree), Some(&mut options))?;
+    diff.print(DiffFormat::Patch, diff_print(&mut buf))?;
+
+    Ok(buf)
+}
+
+/// The patch of the changes to the mappings in the given worktree, without staging them
+///
+/// Safe to use while the Enigma server is running in the worktree, unlike [`create_patch`]
+pub async fn current_patch<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, Box<dyn Error>> {
     let settings = read_settings().await?;
-    let repo = open_repo()?;
+    let repo = open_worktree(path)?;
+
+    Ok(diff_working_tree_bytes(&repo, &[settings.mappings_file.as_str()])?)
+}
+
+/// Stage the changes to the mappings in the given worktree, and return their patch
+pub async fn create_patch<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, Box<dyn Error>> {
+    let settings = read_settings().await?;
+    let repo = open_worktree(path)?;
 
     // Stage changes
     add(&repo, &[settings.mappings_file.as_str()])?;
@@ -334,6 +552,142 @@ pub async fn create_patch() -> Result<Vec<u8>, Box<dyn Error>> {
     Ok(patch)
 }
 
+/// Apply a patch created by [`create_patch`] to the working tree and index
+///
+/// Nothing is applied if a file doesn't apply cleanly, the paths of the conflicting files are returned instead
+///
+/// Equivalent to `git apply --index`
+pub fn apply_patch(repo: &Repository, patch: &[u8]) -> Result<Result<(), Vec<String>>, Box<dyn Error>> {
+    if patch.is_empty() {
+        return Ok(Ok(()));
+    }
+
+    let diff = Diff::from_buffer(patch)?;
+
+    // Check each file on its own, to report all of the conflicts at once
+    let mut conflicts = vec![];
+    for delta in diff.deltas() {
+        let Some(path) = delta.new_file().path().or(delta.old_file().path()) else {
+            continue;
+        };
+
+        let mut options = ApplyOptions::new();
+        options.check(true);
+        options.delta_callback(|d| d.is_some_and(|d| d.new_file().path().or(d.old_file().path()) == Some(path)));
+        if repo.apply(&diff, ApplyLocation::Both, Some(&mut options)).is_err() {
+            conflicts.push(path.to_string_lossy().to_string());
+        }
+    }
+    if !conflicts.is_empty() {
+        return Ok(Err(conflicts));
+    }
+
+    repo.apply(&diff, ApplyLocation::Both, None)?;
+
+    Ok(Ok(()))
+}
+
+/// Apply a patch created by [`create_patch`] to the checked out branch and commit it
+///
+/// The repository must have no uncommitted changes. Returns the id of the new commit, or the conflicting files
+/// if the patch doesn't apply cleanly
+pub fn commit_patch(repo: &Repository, patch: &[u8], message: &str) -> Result<Result<Oid, Vec<String>>, Box<dyn Error>> {
+    if patch.is_empty() {
+        throw!("The patch is empty, there's nothing to commit")
+    }
+    // Build outputs such as the Enigma jar are left around in the main repository
+    let mut options = StatusOptions::new();
+    options.include_ignored(false).include_untracked(false);
+    if !repo.statuses(Some(&mut options))?.is_empty() {
+        throw!("The repository has uncommitted changes")
+    }
+
+    if let Err(conflicts) = apply_patch(repo, patch)? {
+        return Ok(Err(conflicts));
+    }
+
+    match commit(repo, message) {
+        Ok(oid) => Ok(Ok(oid)),
+        Err(e) => {
+            // Don't leave the patch staged
+            hard_reset(repo)?;
+            Err(e)?
+        }
+    }
+}
+
+/// Format a patch as an email, so it can be applied with `git am`
+///
+/// The first line of the message is the subject, and the rest its body
+///
+/// Equivalent to `git format-patch --stdout`, without the diffstat
+pub fn format_patch(commit: Option<&str>, author: &Signature, date: DateTime<Utc>, message: &str, patch: &[u8]) -> Vec<u8> {
+    let message = message.trim();
+    let (subject, body) = message.split_once('\n').unwrap_or((message, ""));
+    let body = body.trim();
+
+    let mut email = format!("From {} Mon Sep 17 00:00:00 2001\n", commit.unwrap_or(ZERO_COMMIT));
+    email.push_str(&format!("From: {} <{}>\n", encode_header(author.name().unwrap_or_default()),
+                            author.email().unwrap_or_default()));
+    email.push_str(&format!("Date: {}\n", date.to_rfc2822()));
+    email.push_str(&format!("Subject: [PATCH] {}\n", encode_header(subject.trim())));
+    email.push_str("MIME-Version: 1.0\nContent-Type: text/plain; charset=UTF-8\nContent-Transfer-Encoding: 8bit\n\n");
+    if !body.is_empty() {
+        email.push_str(body);
+        email.push('\n');
+    }
+    email.push_str("---\n");
+
+    let mut bytes = email.into_bytes();
+    bytes.extend_from_slice(patch);
+    bytes.extend_from_slice(b"-- \nEnigma CoLab\n");
+    bytes
+}
+
+/// Encode a header value as an RFC 2047 encoded word, if it isn't plain ASCII
+fn encode_header(value: &str) -> String {
+    if value.chars().all(|c| c.is_ascii() && !c.is_ascii_control()) {
+        return value.to_string();
+    }
+
+    let encoded: String = value.bytes()
+        .map(|b| match b {
+            b' ' => "_".to_string(),
+            b if b.is_ascii_alphanumeric() => (b as char).to_string(),
+            b => format!("={b:02X}"),
+        })
+        .collect();
+    format!("=?UTF-8?q?{encoded}?=")
+}
+
+/// The contents of the files a patch adds or modifies, once applied to the given revision
+///
+/// Deleted files are left out. Neither the working tree nor the index are touched
+pub fn patched_files(repo: &Repository, rev: &str, patch: &[u8]) -> Result<Vec<FileContents>, Box<dyn Error>> {
+    if patch.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let tree = repo.revparse_single(rev)?.peel_to_tree()?;
+    let diff = Diff::from_buffer(patch)?;
+    let index = repo.apply_to_tree(&tree, &diff, None)?;
+
+    let mut files = vec![];
+    for delta in diff.deltas() {
+        if delta.status() == Delta::Deleted {
+            continue;
+        }
+        let Some(path) = delta.new_file().path() else {
+            continue;
+        };
+
+        let entry = index.get_path(path, 0).ok_or("Patched file missing from the index")?;
+        files.push((path.to_string_lossy().to_string(), repo.find_blob(entry.id)?.content().to_vec()));
+    }
+
+    Ok(files)
+}
+
 /// Equivalent to `git reset --hard`
 pub fn hard_reset(repo: &Repository) -> Git2Result<()> {
     let head = repo.head()?;
@@ -376,9 +730,9 @@ pub fn clean_repo(repo: &Repository, path: Option<String>) -> Result<(), Box<dyn
     Ok(())
 }
 
-pub async fn clear_working_tree() -> Result<(), Box<dyn Error>> {
+pub async fn clear_working_tree<P: AsRef<Path>>(path: P) -> Result<(), Box<dyn Error>> {
     let settings = read_settings().await?;
-    let repo = open_repo()?;
+    let repo = open_worktree(path)?;
 
     // Remove staged and working dir changes
     hard_reset(&repo)?;
@@ -580,7 +934,7 @@ mod tests {
         commit(&upstream, "Update file.txt")?;
 
         let pre_fetch = repo.revparse_single("refs/remotes/origin/master")?.id();
-        fetch_repo(&repo)?;
+        fetch_repo(&repo, &RemoteSettings::default())?;
         let post_fetch = repo.revparse_single("refs/remotes/origin/master")?.id();
 
         assert_ne!(pre_fetch, post_fetch, "refs/remotes/origin/master wasn't updated");
@@ -611,7 +965,7 @@ mod tests {
         assert!(old_head.is_some(), "Invalid HEAD in the cloned repo");
         let old_head = old_head.unwrap();
 
-        let pull_result = pull_repo(&repo)?;
+        let pull_result = pull_repo(&repo, &RemoteSettings::default())?;
         assert!(pull_result.is_ok());
         let new_head = pull_result.unwrap();
 
@@ -625,6 +979,30 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_push() -> Result<(), Box<dyn Error>> {
+        let (repo_dir, repo) = open_test_repo()?;
+        let remote_dir = tempfile::Builder::new().prefix("testrepo_remote").tempdir()?;
+        let remote = Repository::init_bare(remote_dir.path())?;
+        repo.remote("backup", remote_dir.path().to_str().expect("Path contains invalid UTF-8"))?;
+        let settings = RemoteSettings { push_remote: "backup".to_string(), ..RemoteSettings::default() };
+
+        write_assert!(repo_dir.path().join("file.txt"), "Lorem ipsum dolor sit amet\nNew line\n");
+        add(&repo, &["file.txt"])?;
+        let head = commit(&repo, "Update file.txt")?;
+
+        let updates = push_repo(&repo, &settings)?;
+        assert_eq!(vec![PushUpdate { refname: "refs/heads/master".to_string(), rejection: None }], updates);
+        assert_eq!(head, remote.refname_to_id("refs/heads/master")?, "Remote branch wasn't updated");
+
+        let unknown = RemoteSettings { push_remote: "unknown".to_string(), ..RemoteSettings::default() };
+        assert!(push_repo(&repo, &unknown).is_err());
+
+        remote_dir.close()?;
+        repo_dir.close()?;
+        Ok(())
+    }
+
     #[test]
     fn test_diff() -> Result<(), Box<dyn Error>> {
         let (repo_dir, repo) = open_test_repo()?;
@@ -666,6 +1044,201 @@ index 0000000..3676365
         Ok(())
     }
 
+    #[test]
+    fn test_diff_working_tree() -> Result<(), Box<dyn Error>> {
+        let (repo_dir, repo) = open_test_repo()?;
+        let repo_path = repo_dir.path();
+
+        write_assert!(repo_path.join("file.txt"), "New line\nLorem ipsum dolor sit amet\n");
+        fs::create_dir(repo_path.join("cats"))?;
+        write_assert!(repo_path.join("cats/meow.txt"), "Meow\n");
+        write_assert!(repo_path.join("foo.txt"), "Foo bar baz\n");
+
+        let diff = diff_working_tree_bytes(&repo, &["file.txt", "cats/"])?;
+        assert!(repo.index()?.get_path(Path::new("cats/meow.txt"), 0).is_none(), "The index was modified");
+        assert_eq!(Status::WT_NEW, repo.status_file(Path::new("cats/meow.txt"))?);
+
+        add(&repo, &["file.txt", "cats/"])?;
+        assert_eq!(diff_bytes(&repo)?, diff, "Not the same as the staged diff");
+
+        repo_dir.close()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_patch() -> Result<(), Box<dyn Error>> {
+        let (repo_dir, repo) = open_test_repo()?;
+        let file = repo_dir.path().join("file.txt");
+        let original = fs::read_to_string(&file)?;
+
+        let new_contents = write_assert!(file, "Lorem ipsum dolor sit amet\nNew line\n");
+        add(&repo, &["file.txt"])?;
+        let patch = diff_bytes(&repo)?;
+        hard_reset(&repo)?;
+        assert_eq!(original, fs::read_to_string(&file)?);
+
+        assert_eq!(Ok(()), apply_patch(&repo, &patch)?);
+        assert_eq!(new_contents, fs::read_to_string(&file)?, "Patch wasn't applied");
+        assert_eq!(patch, diff_bytes(&repo)?, "Patch wasn't staged");
+        hard_reset(&repo)?;
+
+        write_assert!(file, "Something else entirely\n");
+        assert_eq!(Err(vec!["file.txt".to_string()]), apply_patch(&repo, &patch)?);
+        assert_eq!("Something else entirely\n", fs::read_to_string(&file)?, "Conflicting patch was applied");
+
+        assert_eq!(Ok(()), apply_patch(&repo, &[])?);
+
+        repo_dir.close()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_commit_patch() -> Result<(), Box<dyn Error>> {
+        let (repo_dir, repo) = open_test_repo()?;
+        let file = repo_dir.path().join("file.txt");
+
+        let new_contents = write_assert!(file, "Lorem ipsum dolor sit amet\nNew line\n");
+        add(&repo, &["file.txt"])?;
+        let patch = diff_bytes(&repo)?;
+        assert!(commit_patch(&repo, &patch, "Dirty").is_err(), "Committed on top of uncommitted changes");
+        hard_reset(&repo)?;
+
+        // Ignored and untracked files don't count as uncommitted changes, and aren't committed
+        fs::write(repo_dir.path().join(".git/info/exclude"), "*.jar\n")?;
+        write_assert!(repo_dir.path().join("server.jar"), "Not a jar\n");
+        write_assert!(repo_dir.path().join("notes.txt"), "Untracked\n");
+
+        let parent = repo.head()?.peel_to_commit()?.id();
+        let oid = commit_patch(&repo, &patch, "Merge session")?.expect("Patch conflicted");
+        let merged = repo.find_commit(oid)?;
+        assert_eq!(Some("Merge session"), merged.message());
+        assert_eq!(parent, merged.parent_id(0)?);
+        assert_eq!(new_contents, fs::read_to_string(&file)?);
+        assert_eq!(Status::CURRENT, repo.status_file(Path::new("file.txt"))?, "Changes left after committing");
+        assert_eq!(Status::IGNORED, repo.status_file(Path::new("server.jar"))?);
+        assert_eq!(Status::WT_NEW, repo.status_file(Path::new("notes.txt"))?);
+
+        write_assert!(file, "Something else entirely\n");
+        add(&repo, &["file.txt"])?;
+        commit(&repo, "Replace file.txt")?;
+        assert_eq!(Err(vec!["file.txt".to_string()]), commit_patch(&repo, &patch, "Conflicting")?);
+        assert!(commit_patch(&repo, &[], "Empty").is_err());
+
+        repo_dir.close()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_patch() -> Result<(), Box<dyn Error>> {
+        let author = Signature::now("Meow", "me@meow.com")?;
+        let date = DateTime::parse_from_rfc3339("2026-10-01T10:00:00Z")?.to_utc();
+        let patch = b"diff --git a/file.txt b/file.txt\n";
+
+        let email = format_patch(None, &author, date, "Worldgen\n\nMap the package\n\nCollaborators: alice\n", patch);
+        assert_eq!(r#"From 0000000000000000000000000000000000000000 Mon Sep 17 00:00:00 2001
+From: Meow <me@meow.com>
+Date: Thu, 1 Oct 2026 10:00:00 +0000
+Subject: [PATCH] Worldgen
+MIME-Version: 1.0
+Content-Type: text/plain; charset=UTF-8
+Content-Transfer-Encoding: 8bit
+
+Map the package
+
+Collaborators: alice
+---
+diff --git a/file.txt b/file.txt
+-- 
+Enigma CoLab
+"#, from_utf8(&email)?);
+
+        let email = format_patch(Some("abc"), &author, date, "Génération", patch);
+        let email = from_utf8(&email)?;
+        assert!(email.starts_with("From abc "));
+        assert!(email.contains("Subject: [PATCH] =?UTF-8?q?G=C3=A9n=C3=A9ration?=\n"));
+        assert!(email.contains("\n\n---\n"), "Empty body not left out");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_patched_files() -> Result<(), Box<dyn Error>> {
+        let (repo_dir, repo) = open_test_repo()?;
+        let rev = get_repo_head(&repo)?;
+
+        let new_contents = write_assert!(repo_dir.path().join("file.txt"), "Lorem ipsum dolor sit amet\nNew line\n");
+        let new_file = write_assert!(repo_dir.path().join("new.txt"), "New file\n");
+        add(&repo, &["file.txt", "new.txt"])?;
+        let patch = diff_bytes(&repo)?;
+        hard_reset(&repo)?;
+        clean_repo(&repo, None)?;
+
+        let files = patched_files(&repo, &rev, &patch)?;
+        assert_eq!(vec![
+            ("file.txt".to_string(), new_contents.into_bytes()),
+            ("new.txt".to_string(), new_file.into_bytes()),
+        ], files);
+        assert!(repo.statuses(None)?.is_empty(), "Patch was applied to the repository");
+        assert!(patched_files(&repo, &rev, &[])?.is_empty());
+
+        repo_dir.close()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_worktree() -> Result<(), Box<dyn Error>> {
+        let (repo_dir, repo) = open_test_repo()?;
+        let worktrees_dir = tempfile::Builder::new().prefix("testrepo_worktrees").tempdir()?;
+        let worktree_path = worktrees_dir.path().join("session");
+
+        let worktree = add_worktree(&repo, "session", &worktree_path, "master")?;
+        assert_eq!(get_repo_head(&repo)?, get_repo_head(&worktree)?, "Worktree created at a wrong revision");
+        assert_eq!(Some("colab/session"), worktree.head()?.shorthand());
+
+        let file = worktree_path.join("file.txt");
+        write_assert!(file, "Changed in the worktree\n");
+        add(&worktree, &["file.txt"])?;
+        assert!(!diff_bytes(&worktree)?.is_empty());
+        assert!(diff_bytes(&repo)?.is_empty(), "Worktree changes leaked into the main repo");
+        assert_eq!("Lorem ipsum dolor sit amet\n", fs::read_to_string(repo_dir.path().join("file.txt"))?);
+
+        assert!(add_worktree(&repo, "other", worktrees_dir.path().join("other"), "unknown").is_err());
+
+        remove_worktree(&repo, "session")?;
+        assert!(!worktree_path.exists(), "Worktree directory wasn't removed");
+        assert!(repo.find_branch("colab/session", BranchType::Local).is_err(), "Worktree branch wasn't deleted");
+
+        worktrees_dir.close()?;
+        repo_dir.close()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_ref_branch() -> Result<(), Box<dyn Error>> {
+        let (upstream_dir, upstream) = open_test_repo()?;
+        let (repo_dir, repo) = clone_test_repo(&upstream_dir)?;
+
+        let head_commit = upstream.head()?.peel_to_commit()?;
+        upstream.branch("feature", &head_commit, false)?;
+        fetch_repo(&repo, &RemoteSettings::default())?;
+        let head = repo.head()?.peel_to_commit()?;
+        repo.tag_lightweight("v1", head.as_object(), false)?;
+
+        assert_eq!(Some("master".to_string()), head_branch(&repo)?);
+        assert_eq!(Some("master".to_string()), ref_branch(&repo, "master")?);
+        assert_eq!(Some("feature".to_string()), ref_branch(&repo, "feature")?);
+        assert_eq!(Some("feature".to_string()), ref_branch(&repo, "origin/feature")?);
+        assert_eq!(None, ref_branch(&repo, "v1")?);
+        assert_eq!(None, ref_branch(&repo, &head.id().to_string())?);
+
+        repo.set_head_detached(head.id())?;
+        assert_eq!(None, head_branch(&repo)?);
+
+        upstream_dir.close()?;
+        repo_dir.close()?;
+        Ok(())
+    }
+
     #[test]
     fn test_checkout() -> Result<(), Box<dyn Error>> {
         let (upstream_dir, upstream) = open_test_repo()?;
@@ -685,7 +1258,7 @@ index 0000000..3676365
         add(&upstream, &["file.txt"])?;
         let new_head_oid = commit(&upstream, "Update file.txt")?;
 
-        fetch_repo(&repo)?;
+        fetch_repo(&repo, &RemoteSettings::default())?;
         let checkout_oid = repo_checkout(&repo, "test".to_string())?;
 
         assert_eq!(new_head_oid, checkout_oid, "Checked out a wrong ref");
@@ -1,22 +1,60 @@
-use std::convert::Infallible;
-use std::env;
+use std::error::Error;
+use std::time::Duration as StdDuration;
 
-use rocket::{Request, Route};
+use chrono::{DateTime, Duration, Utc};
+use rocket::Route;
 use rocket::form::Form;
 use rocket::fs::NamedFile;
-use rocket::http::{CookieJar, Status};
-use rocket::outcome::IntoOutcome;
-use rocket::outcome::Outcome::Forward;
-use rocket::request::{FlashMessage, FromRequest, Outcome};
+use rocket::http::{ContentType, CookieJar, Header, Status};
+use rocket::http::uri::Host;
+use rocket::request::FlashMessage;
 use rocket::response::{Flash, Redirect};
+use rocket::response::stream::{Event, EventStream};
 use rocket::serde::Deserialize;
+use rocket::Shutdown;
+use rocket::tokio::select;
+use rocket::tokio::time::sleep;
 use rocket_dyn_templates::{context, Template};
 use uuid::Uuid;
 
-use crate::{repo, SessionsState, util};
-use crate::sessions::Session;
+use crate::{LoginAttemptsState, LoginsState, repo, SessionsState, UsersState};
+use crate::{audit, auth, launcher, password, totp};
+use crate::audit::{Action, Filter};
+use crate::auth::{AdminUser, Client, has_role, HostUser, PendingLogin, User};
+use crate::csrf::{CsrfToken, VerifiedCsrf};
+use crate::launcher::LauncherKind;
+use crate::logins::LoginStore;
+use crate::logs::LogTail;
+use crate::password::Verification;
+use crate::repo::PushUpdate;
+use crate::markdown;
+use crate::sessions;
+use crate::sessions::{LogStream, Session, SessionDetails, SessionLimits};
 use crate::settings;
-use crate::settings::{RepoSettings, Settings};
+use crate::settings::{RemoteSettings, RepoSettings, Settings};
+use crate::users::{Account, Role, UserStore};
+
+/// Maximum number of audit log entries shown at once
+const AUDIT_LOG_LIMIT: usize = 500;
+/// How much of the end of a session log is sent before following it
+const LOG_BACKFILL_BYTES: u64 = 64 * 1024;
+const LOG_POLL_INTERVAL: StdDuration = StdDuration::from_millis(500);
+
+/// A file sent as an attachment, so browsers save it instead of displaying it
+#[derive(Responder)]
+struct Download<R> {
+    file: R,
+    disposition: Header<'static>,
+}
+
+impl<R> Download<R> {
+    fn new(file: R, file_name: &str) -> Download<R> {
+        Download {
+            file,
+            disposition: Header::new("Content-Disposition", format!("attachment; filename=\"{file_name}\"")),
+        }
+    }
+}
 
 #[derive(FromForm)]
 struct Login<'r> {
@@ -27,45 +65,96 @@ struct Login<'r> {
 #[derive(FromForm)]
 struct NewSession<'r> {
     password: &'r str,
+    /// Branch or revision to start from, the current branch if empty
+    base: &'r str,
+    title: &'r str,
+    description: &'r str,
+    /// Separated by commas
+    tags: &'r str,
+    max_hours: u16,
+    idle_minutes: u16,
+    /// Session whose patch is applied before starting
+    parent: Option<Uuid>,
 }
 
-#[derive(Debug)]
-struct User(String);
-
-#[derive(Debug)]
-struct AdminUser(String);
+#[derive(FromForm)]
+struct EditSessionDetails<'r> {
+    title: &'r str,
+    description: &'r str,
+    /// Separated by commas
+    tags: &'r str,
+}
 
-#[rocket::async_trait]
-impl<'r> FromRequest<'r> for User {
-    type Error = Infallible;
+#[derive(FromForm)]
+struct RemoteSettingsData {
+    push_remote: String,
+    username: String,
+    /// Left unchanged if empty
+    token: String,
+    ssh_key: String,
+    /// Left unchanged if empty
+    ssh_passphrase: String,
+    /// Clear the stored token and passphrase
+    forget_secrets: bool,
+}
 
-    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        request.cookies()
-            .get_private("session")
-            .and_then(|cookie| cookie.value().parse().ok())
-            .map(User)
-            .or_forward(Status::Unauthorized)
+impl RemoteSettingsData {
+    fn write(self, settings: &mut RemoteSettings) {
+        settings.push_remote = self.push_remote.trim().to_string();
+        settings.username = self.username.trim().to_string();
+        settings.ssh_key = self.ssh_key.trim().to_string();
+        if self.forget_secrets {
+            settings.token.clear();
+            settings.ssh_passphrase.clear();
+        }
+        if !self.token.is_empty() {
+            settings.token = self.token;
+        }
+        if !self.ssh_passphrase.is_empty() {
+            settings.ssh_passphrase = self.ssh_passphrase;
+        }
     }
 }
 
-#[rocket::async_trait]
-impl<'r> FromRequest<'r> for AdminUser {
-    type Error = Infallible;
+#[derive(FromForm)]
+struct NewUser<'r> {
+    name: &'r str,
+    password: &'r str,
+    role: Role,
+}
+
+#[derive(FromForm)]
+struct RoleData {
+    role: Role,
+}
 
-    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        let id = env::var("ADMIN_SESSION_ID");
+#[derive(FromForm)]
+struct NewInvite {
+    role: Role,
+    valid_hours: u16,
+}
 
-        if id.is_ok() {
-            request.cookies()
-                .get_private("session")
-                .and_then(|cookie| cookie.value().parse().ok())
-                .filter(|v| *v == id.unwrap())
-                .map(AdminUser)
-                .or_forward(Status::Unauthorized)
-        } else {
-            Forward(Status::Unauthorized)
-        }
-    }
+#[derive(FromForm)]
+struct TwoFactorCode<'r> {
+    code: &'r str,
+}
+
+#[derive(FromForm)]
+struct PasswordChange<'r> {
+    current_password: &'r str,
+    new_password: &'r str,
+}
+
+#[derive(FromForm)]
+struct NewToken<'r> {
+    name: &'r str,
+}
+
+#[derive(FromForm)]
+struct Registration<'r> {
+    token: &'r str,
+    name: &'r str,
+    password: &'r str,
 }
 
 #[derive(FromForm, Deserialize)]
@@ -77,7 +166,16 @@ struct SettingsData {
     pre_session_cmd: String,
     post_session_cmd: String,
     enigma_args: String,
+    launcher: LauncherKind,
     classpath: String,
+    gradle_task: String,
+    launch_command: String,
+    public_host: String,
+    enigma_port: u16,
+    shutdown_grace_period: u16,
+    max_session_hours: u16,
+    session_idle_minutes: u16,
+    merge_message_template: String,
 }
 
 impl SettingsData {
@@ -89,61 +187,240 @@ impl SettingsData {
         settings.pre_session_cmd = self.pre_session_cmd;
         settings.post_session_cmd = self.post_session_cmd;
         settings.enigma_args = self.enigma_args;
+        settings.launcher = self.launcher;
         settings.classpath = self.classpath;
+        settings.gradle_task = self.gradle_task.trim().to_string();
+        settings.launch_command = self.launch_command;
+        settings.public_host = self.public_host.trim().to_string();
+        settings.enigma_port = self.enigma_port;
+        settings.shutdown_grace_period = self.shutdown_grace_period;
+        settings.max_session_hours = self.max_session_hours;
+        settings.session_idle_minutes = self.session_idle_minutes;
+        // Browsers submit textareas with CRLF line endings
+        settings.merge_message_template = self.merge_message_template.replace("\r\n", "\n");
     }
 }
 
 #[get("/login")]
 fn login(_user: User) -> Redirect {
-    Redirect::to(uri!(index))
+    Redirect::to(uri!(index(_, _)))
 }
 
 #[get("/login", rank = 2)]
-fn login_page(flash: Option<FlashMessage<'_>>) -> Template {
+fn login_page(flash: Option<FlashMessage<'_>>, csrf: CsrfToken) -> Template {
     Template::render("login", context! {
         logged_in: false,
-        msg: flash
+        msg: flash,
+        csrf_token: csrf.0
     })
 }
 
 #[post("/login", data = "<login>")]
-fn login_form(cookies: &CookieJar<'_>, login: Form<Login<'_>>) -> Flash<Redirect> {
-    // TODO: Users, registration, database
-    let user = env::var("USER");
-    let password = env::var("PASSWORD_HASH");
-    if user.is_ok() && password.is_ok() {
-        if login.user == user.unwrap() && util::sha3_256(&login.password) == password.unwrap() {
-            let id = env::var("ADMIN_SESSION_ID");
-            if id.is_ok() {
-                cookies.add_private(("session", id.unwrap()));
+async fn login_form(_csrf: VerifiedCsrf, client: Client, cookies: &CookieJar<'_>, users: UsersState<'_>,
+                    logins: LoginsState<'_>, attempts: LoginAttemptsState<'_>, login: Form<Login<'_>>) -> Flash<Redirect> {
+    let ip = client.ip;
+    let now = Utc::now();
+    // The attempt counts as failed until the password is verified, so parallel guesses are throttled too
+    if let Err(until) = attempts.lock().await.reserve(ip, login.user, now) {
+        return Flash::error(Redirect::to(uri!(login_page)), throttled_msg(until));
+    }
+
+    // Hashing is slow on purpose, so the password is verified without holding any lock
+    let credentials = users.lock().await.password_hash(login.user);
+    let verification = password::verify_account_async(login.password, credentials.as_ref().map(|(_, hash)| hash.clone())).await;
+    let new_hash = match verification {
+        Verification::NeedsRehash => password::hash_new(login.password).await
+            .map_err(|e| eprintln!("Failed to rehash the password of '{}': {e}", login.user))
+            .ok(),
+        _ => None,
+    };
+
+    let mut attempts = attempts.lock().await;
+    let mut users = users.lock().await;
+    let account = match credentials {
+        Some((id, old_hash)) if verification != Verification::Invalid => {
+            if let Some(new_hash) = new_hash {
+                if let Err(e) = users.upgrade_password_hash(id, &old_hash, new_hash) {
+                    eprintln!("Failed to rehash the password of '{}': {e}", login.user);
+                }
             }
+            users.get(id).filter(|a| !a.disabled)
+        },
+        _ => None,
+    };
+
+    match account {
+        Some(account) => {
+            attempts.record_success(ip, login.user);
+            start_login(cookies, &mut *logins.lock().await, client, account)
+        },
+        None => Flash::error(Redirect::to(uri!(login_page)), "Invalid user/password")
+    }
+}
+
+fn throttled_msg(until: DateTime<Utc>) -> String {
+    let seconds = (until - Utc::now()).num_seconds().max(1);
+    format!("Too many failed attempts, try again in {seconds} seconds")
+}
+
+/// Log in after checking the password, asking for the second factor first if needed
+fn start_login(cookies: &CookieJar<'_>, logins: &mut LoginStore, client: Client, account: &Account) -> Flash<Redirect> {
+    if account.has_two_factor() {
+        auth::start_pending_login(cookies, account.id);
+        Flash::success(Redirect::to(uri!(two_factor_page)), "Enter your two-factor authentication code")
+    } else if account.requires_two_factor() {
+        auth::start_pending_login(cookies, account.id);
+        Flash::success(Redirect::to(uri!(two_factor_setup_page)), "Two-factor authentication is required for your account")
+    } else {
+        finish_login(cookies, logins, client, account.id, "Logged in")
+    }
+}
+
+fn finish_login(cookies: &CookieJar<'_>, logins: &mut LoginStore, client: Client, id: Uuid, msg: &str) -> Flash<Redirect> {
+    match auth::log_in(cookies, logins, client, id) {
+        Ok(_) => Flash::success(Redirect::to(uri!(index(_, _))), msg),
+        Err(e) => Flash::error(Redirect::to(uri!(login_page)), format!("Failed to log in: {e}"))
+    }
+}
+
+#[get("/login/two-factor")]
+fn two_factor_page(_pending: PendingLogin, flash: Option<FlashMessage<'_>>, csrf: CsrfToken) -> Template {
+    Template::render("two_factor", context! {
+        logged_in: false,
+        msg: flash,
+        csrf_token: csrf.0,
+    })
+}
+
+#[post("/login/two-factor", data = "<data>")]
+async fn two_factor_form(pending: PendingLogin, client: Client, cookies: &CookieJar<'_>, users: UsersState<'_>,
+                         logins: LoginsState<'_>, attempts: LoginAttemptsState<'_>, data: Form<TwoFactorCode<'_>>) -> Flash<Redirect> {
+    let ip = client.ip;
+    let redirect = Redirect::to(uri!(two_factor_page));
+    let name = match users.lock().await.get(pending.0) {
+        Some(account) => account.name.clone(),
+        None => return Flash::error(Redirect::to(uri!(login_page)), "User not found"),
+    };
+
+    let now = Utc::now();
+    let mut attempts = attempts.lock().await;
+    if let Err(until) = attempts.reserve(ip, &name, now) {
+        return Flash::error(redirect, throttled_msg(until));
+    }
+
+    let mut users = users.lock().await;
+    let mut logins = logins.lock().await;
+    match users.verify_two_factor(pending.0, data.code) {
+        Ok(true) => {
+            attempts.record_success(ip, &name);
+            finish_login(cookies, &mut logins, client, pending.0, "Logged in")
+        },
+        Ok(false) => Flash::error(redirect, "Invalid code"),
+        Err(e) => Flash::error(redirect, format!("Failed to check the code: {e}"))
+    }
+}
 
-            return Flash::success(Redirect::to(uri!(index)), "Logged in");
+fn two_factor_setup(users: &mut UserStore, id: Uuid, action: &str, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, logged_in: bool) -> Option<Template> {
+    let secret = match users.start_two_factor_setup(id) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Failed to set up two-factor authentication: {e}");
+            return None;
         }
+    };
+    let account = users.get(id)?;
+    let uri = totp::otpauth_uri(&secret, &account.name)?;
+
+    Some(Template::render("two_factor_setup", context! {
+        logged_in: logged_in,
+        admin: logged_in && account.role == Role::Admin,
+        msg: flash,
+        qr_code: totp::qr_code_svg(&uri),
+        uri: uri,
+        secret: secret,
+        action: action,
+        csrf_token: csrf.0,
+    }))
+}
+
+fn recovery_codes_page(codes: Vec<String>, admin: bool, csrf_token: String) -> Template {
+    Template::render("recovery_codes", context! {
+        logged_in: true,
+        admin: admin,
+        codes: codes,
+        csrf_token: csrf_token,
+    })
+}
+
+#[get("/login/two-factor/setup")]
+async fn two_factor_setup_page(pending: PendingLogin, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, users: UsersState<'_>) -> Option<Template> {
+    let mut users = users.lock().await;
+    two_factor_setup(&mut users, pending.0, "/login/two-factor/setup", flash, csrf, false)
+}
+
+#[post("/login/two-factor/setup", data = "<data>")]
+async fn two_factor_setup_form(pending: PendingLogin, client: Client, cookies: &CookieJar<'_>, users: UsersState<'_>,
+                               logins: LoginsState<'_>, attempts: LoginAttemptsState<'_>, data: Form<TwoFactorCode<'_>>)
+                               -> Result<Template, Flash<Redirect>> {
+    let ip = client.ip;
+    let redirect = Redirect::to(uri!(two_factor_setup_page));
+    let name = match users.lock().await.get(pending.0) {
+        Some(account) => account.name.clone(),
+        None => return Err(Flash::error(Redirect::to(uri!(login_page)), "User not found")),
+    };
+
+    let mut attempts = attempts.lock().await;
+    if let Err(until) = attempts.reserve(ip, &name, Utc::now()) {
+        return Err(Flash::error(redirect, throttled_msg(until)));
     }
 
-    Flash::error(Redirect::to(uri!(login_page)), "Invalid user/password")
+    let mut users = users.lock().await;
+    let mut logins = logins.lock().await;
+    match users.confirm_two_factor_setup(pending.0, data.code) {
+        Ok(codes) => {
+            attempts.record_success(ip, &name);
+            let csrf_token = match auth::log_in(cookies, &mut logins, client, pending.0) {
+                Ok(token) => token,
+                Err(e) => return Err(Flash::error(Redirect::to(uri!(login_page)), format!("Failed to log in: {e}"))),
+            };
+            let admin = users.get(pending.0).is_some_and(|a| a.role == Role::Admin);
+            Ok(recovery_codes_page(codes, admin, csrf_token))
+        },
+        Err(e) => Err(Flash::error(redirect, format!("Failed to enable two-factor authentication: {e}")))
+    }
+}
+
+#[get("/login/two-factor", rank = 2)]
+fn two_factor_redirect() -> Redirect {
+    Redirect::to(uri!(login_page))
 }
 
-#[get("/logout")]
-fn logout(cookies: &CookieJar<'_>) -> Flash<Redirect> {
-    cookies.remove_private("session");
-    Flash::success(Redirect::to(uri!(index)), "Logged out")
+#[get("/login/two-factor/setup", rank = 2)]
+fn two_factor_setup_redirect() -> Redirect {
+    Redirect::to(uri!(login_page))
+}
+
+#[post("/logout")]
+async fn logout(_csrf: VerifiedCsrf, cookies: &CookieJar<'_>, logins: LoginsState<'_>) -> Flash<Redirect> {
+    match auth::log_out(cookies, &mut *logins.lock().await) {
+        Ok(_) => Flash::success(Redirect::to(uri!(index(_, _))), "Logged out"),
+        Err(e) => Flash::error(Redirect::to(uri!(index(_, _))), format!("Failed to log out: {e}"))
+    }
 }
 
 #[get("/settings")]
-async fn settings_page(_admin_user: AdminUser, flash: Option<FlashMessage<'_>>) -> Template {
-    let (settings, err) = match settings::read_settings().await {
+async fn settings_page(_admin_user: AdminUser, flash: Option<FlashMessage<'_>>, csrf: CsrfToken) -> Template {
+    let (mut settings, err) = match settings::read_settings().await {
         Ok(s) => (s, None),
         Err(e) => (Settings::default(), Some(format!("Failed to read settings: {e}")))
     };
+    // Only tell whether the secrets are set, they're never sent back
+    let has_token = !std::mem::take(&mut settings.remote.token).is_empty();
+    let has_passphrase = !std::mem::take(&mut settings.remote.ssh_passphrase).is_empty();
 
     let cloned = repo::is_cloned();
     let branches = if cloned {
-        Some(match repo::list_local_branches().await {
-            Ok(b) => b,
-            Err(_) => Vec::new(),
-        })
+        Some(repo::list_local_branches().await.unwrap_or_default())
     } else {
         None
     };
@@ -152,50 +429,76 @@ async fn settings_page(_admin_user: AdminUser, flash: Option<FlashMessage<'_>>)
         logged_in: true,
         admin: true,
         settings: settings,
+        has_token: has_token,
+        has_passphrase: has_passphrase,
+        launchers: LauncherKind::ALL,
         cloned: cloned,
         error: err,
         msg: flash,
         branches: branches,
+        csrf_token: csrf.0,
     })
 }
 
-async fn update_settings<T: FnOnce(&mut Settings)>(updater: T) -> Option<String> {
+/// Update the settings, returning a description of the changes
+async fn update_settings<T: FnOnce(&mut Settings)>(updater: T) -> Result<String, String> {
     let mut settings = match settings::read_settings().await {
         Ok(s) => s,
         Err(e) => {
             println!("{}", e);
-            return Some(format!("Failed to read settings: {e}"))
+            return Err(format!("Failed to read settings: {e}"))
         }
     };
 
+    let old = settings.clone();
     updater(&mut settings);
 
     match settings::write_settings(&settings).await {
-        Ok(_) => None,
+        Ok(_) => Ok(settings.describe_changes(&old)),
         Err(e) => {
             println!("{}", e);
-            Some(format!("Failed to write settings: {e}"))
+            Err(format!("Failed to write settings: {e}"))
         }
     }
 }
 
+/// Record an admin action in the audit log, and report its outcome
+fn audited(user: &User, action: Action, details: &str, redirect: Redirect, result: Result<String, String>) -> Flash<Redirect> {
+    audit::record(user, action, details, &result);
+
+    match result {
+        Ok(msg) => Flash::success(redirect, msg),
+        Err(msg) => Flash::error(redirect, msg)
+    }
+}
+
 #[post("/settings", data = "<settings_data>")]
-async fn post_settings(_admin_user: AdminUser, settings_data: Form<SettingsData>) -> Flash<Redirect> {
-    let redirect = Redirect::to(uri!(index));
+async fn post_settings(admin_user: AdminUser, settings_data: Form<SettingsData>) -> Flash<Redirect> {
+    let redirect = Redirect::to(uri!(index(_, _)));
 
     match update_settings(|settings| settings_data.into_inner().write(settings)).await {
-        Some(msg) => Flash::error(redirect, msg),
-        None => Flash::success(redirect, "Settings updated")
+        Ok(changes) => audited(&admin_user.0, Action::UpdateSettings, &changes, redirect, Ok("Settings updated".to_string())),
+        Err(msg) => audited(&admin_user.0, Action::UpdateSettings, "", redirect, Err(msg))
     }
 }
 
 #[post("/settings/repo", data = "<repo_settings>")]
-async fn post_repo_settings(_admin_user: AdminUser, repo_settings: Form<RepoSettings>) -> Flash<Redirect> {
+async fn post_repo_settings(admin_user: AdminUser, repo_settings: Form<RepoSettings>) -> Flash<Redirect> {
     let redirect = Redirect::to(uri!(settings_page));
 
     match update_settings(|settings| settings.repo = repo_settings.into_inner()).await {
-        Some(msg) => Flash::error(redirect, msg),
-        None => Flash::success(redirect, "Settings updated")
+        Ok(changes) => audited(&admin_user.0, Action::UpdateRepoSettings, &changes, redirect, Ok("Settings updated".to_string())),
+        Err(msg) => audited(&admin_user.0, Action::UpdateRepoSettings, "", redirect, Err(msg))
+    }
+}
+
+#[post("/settings/remote", data = "<remote_data>")]
+async fn post_remote_settings(admin_user: AdminUser, remote_data: Form<RemoteSettingsData>) -> Flash<Redirect> {
+    let redirect = Redirect::to(uri!(settings_page));
+
+    match update_settings(|settings| remote_data.into_inner().write(&mut settings.remote)).await {
+        Ok(changes) => audited(&admin_user.0, Action::UpdateSettings, &changes, redirect, Ok("Settings updated".to_string())),
+        Err(msg) => audited(&admin_user.0, Action::UpdateSettings, "", redirect, Err(msg))
     }
 }
 
@@ -209,16 +512,25 @@ fn settings_redirect() -> Redirect {
     Redirect::to(uri!(login))
 }
 
-#[get("/")]
-async fn index(user: Option<User>, flash: Option<FlashMessage<'_>>, sessions: SessionsState<'_>) -> Template {
+#[get("/?<text>&<tag>")]
+async fn index(user: Option<User>, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, sessions: SessionsState<'_>, text: Option<String>,
+               tag: Option<String>) -> Template {
+    let filter = sessions::Filter { text, tag };
     let mut sessions = sessions.lock().await;
     let mut running = vec![];
     let mut recent = vec![];
 
-    let mut iter = sessions.iter_mut();
-    while let Some(session) = iter.next() {
-        if session.check_is_running().expect("Failed to check the session status") {
-            running.push(session);
+    for session in sessions.iter_mut() {
+        session.check_is_running().expect("Failed to check the session status");
+        if !filter.matches(session) {
+            continue;
+        }
+
+        if session.state.is_active() {
+            running.push(context! {
+                deadline: session.deadline(),
+                session: session,
+            });
         } else {
             recent.push(session);
         }
@@ -226,151 +538,787 @@ async fn index(user: Option<User>, flash: Option<FlashMessage<'_>>, sessions: Se
 
     Template::render("index", context! {
         logged_in: user.is_some(),
-        admin: user.filter(|v| {v.0 == env::var("ADMIN_SESSION_ID").unwrap_or_default()}).is_some(),
+        admin: has_role(&user, Role::Admin),
+        host: has_role(&user, Role::SessionHost),
         msg: flash,
+        csrf_token: csrf.0,
         cloned: repo::is_cloned(),
         sessions: context! {
             running,
             recent
-        }
+        },
+        filter: context! {
+            text: filter.text,
+            tag: filter.tag,
+        },
     })
 }
 
 #[post("/clone")]
-async fn clone_repo(_admin: AdminUser) -> Flash<Redirect> {
+async fn clone_repo(admin_user: AdminUser) -> Flash<Redirect> {
     let redirect = Redirect::to(uri!(settings_page));
     if repo::is_cloned() {
-        return Flash::error(redirect, "A repository already exists, can't clone");
+        return audited(&admin_user.0, Action::CloneRepo, "", redirect, Err("A repository already exists, can't clone".to_string()));
     }
 
     // TODO: Send "cloning..." response, update once done?
-    match repo::clone().await {
-        Ok((branch, rev)) =>
-            Flash::success(redirect, format!("Cloned repo, with branch '{branch}' at {rev}")),
-        Err(e) => Flash::error(redirect, format!("Failed to clone repo: {e}"))
-    }
+    let result = match repo::clone().await {
+        Ok((branch, rev)) => Ok(format!("Cloned repo, with branch '{branch}' at {rev}")),
+        Err(e) => Err(format!("Failed to clone repo: {e}"))
+    };
+    audited(&admin_user.0, Action::CloneRepo, "", redirect, result)
 }
 
 #[post("/fetch")]
-async fn fetch(_admin_user: AdminUser) -> Flash<Redirect> {
+async fn fetch(admin_user: AdminUser) -> Flash<Redirect> {
     let redirect = Redirect::to(uri!(settings_page));
-    match repo::fetch() {
-        Ok(_) => Flash::success(redirect, "Fetched remote"),
-        Err(e) => Flash::error(redirect, format!("Failed to fetch repo: {e}"))
-    }
+    let result = match repo::fetch().await {
+        Ok(_) => Ok("Fetched remote".to_string()),
+        Err(e) => Err(format!("Failed to fetch repo: {e}"))
+    };
+    audited(&admin_user.0, Action::Fetch, "", redirect, result)
 }
 
 #[post("/pull")]
-async fn pull(_admin_user: AdminUser) -> Flash<Redirect> {
+async fn pull(admin_user: AdminUser) -> Flash<Redirect> {
     let redirect = Redirect::to(uri!(settings_page));
 
-    match repo::pull().await {
+    let result = match repo::pull().await {
         Ok(res) => { match res {
-            Ok(rev) => Flash::success(redirect, format!("Pulled remote: HEAD is now at {rev}")),
-            Err(msg) => Flash::success(redirect, format!("Not updated: {msg}"))
+            Ok(rev) => Ok(format!("Pulled remote: HEAD is now at {rev}")),
+            Err(msg) => Ok(format!("Not updated: {msg}"))
         } },
-        Err(e) => Flash::error(redirect, format!("Failed to pull from repo: {e}"))
-    }
+        Err(e) => Err(format!("Failed to pull from repo: {e}"))
+    };
+    audited(&admin_user.0, Action::Pull, "", redirect, result)
+}
+
+#[post("/push")]
+async fn push(admin_user: AdminUser) -> Flash<Redirect> {
+    let redirect = Redirect::to(uri!(settings_page));
+
+    let result = match repo::push().await {
+        Ok(updates) if updates.is_empty() => Ok("Nothing to push".to_string()),
+        Ok(updates) => {
+            let describe = |u: &PushUpdate| match &u.rejection {
+                Some(reason) => format!("{} rejected: {reason}", u.refname),
+                None => format!("{} pushed", u.refname),
+            };
+            let summary = updates.iter().map(describe).collect::<Vec<_>>().join(", ");
+
+            if updates.iter().any(|u| u.rejection.is_some()) {
+                Err(format!("Push rejected: {summary}"))
+            } else {
+                Ok(format!("Pushed remote: {summary}"))
+            }
+        },
+        Err(e) => Err(format!("Failed to push to remote: {e}"))
+    };
+    audited(&admin_user.0, Action::Push, "", redirect, result)
 }
 
 #[post("/checkout", data = "<repo_settings>")]
-async fn checkout(_admin_user: AdminUser, repo_settings: Form<RepoSettings>) -> Flash<Redirect> {
+async fn checkout(admin_user: AdminUser, repo_settings: Form<RepoSettings>) -> Flash<Redirect> {
     let redirect = Redirect::to(uri!(settings_page));
 
     let branch = repo_settings.branch.clone();
-    if let Some(msg) = update_settings(|settings| settings.repo = repo_settings.into_inner()).await {
-        return Flash::error(redirect, msg);
+    let details = format!("branch: {branch}");
+    if let Err(msg) = update_settings(|settings| settings.repo = repo_settings.into_inner()).await {
+        return audited(&admin_user.0, Action::Checkout, &details, redirect, Err(msg));
     }
 
-    match repo::checkout().await {
-        Ok(rev) => Flash::success(redirect, format!("Checked out {branch}: HEAD is now at {rev}")),
-        Err(e) => Flash::error(redirect, format!("Failed to checkout {branch}: {e}"))
-    }
+    let result = match repo::checkout().await {
+        Ok(rev) => Ok(format!("Checked out {branch}: HEAD is now at {rev}")),
+        Err(e) => Err(format!("Failed to checkout {branch}: {e}"))
+    };
+    audited(&admin_user.0, Action::Checkout, &details, redirect, result)
 }
 
-#[get("/sessions/new")]
-fn new_session_page(_admin_user: AdminUser) -> Template {
+#[get("/sessions/new?<parent>")]
+async fn new_session_page(host_user: HostUser, csrf: CsrfToken, parent: Option<Uuid>, sessions: SessionsState<'_>) -> Template {
+    let settings = settings::read_settings().await.unwrap_or_default();
+    let sessions = sessions.lock().await;
+    let parent = parent.and_then(|id| sessions.iter().find(|s| s.id == id));
+
     Template::render("new_session", context! {
         logged_in: true,
-        admin: true
+        admin: host_user.0.has_role(Role::Admin),
+        password: sessions::generate_password(),
+        branch: settings.repo.branch,
+        max_hours: settings.max_session_hours,
+        idle_minutes: settings.session_idle_minutes,
+        branches: repo::list_local_branches().await.unwrap_or_default(),
+        parent: parent,
+        csrf_token: csrf.0,
     })
 }
 
 #[post("/sessions/new", data = "<data>")]
-async fn new_session_form(_admin_user: AdminUser, sessions: SessionsState<'_>, data: Form<NewSession<'_>>) -> Flash<Redirect> {
-    let error_redirect = Redirect::to(uri!(index));
+async fn new_session_form(host_user: HostUser, sessions: SessionsState<'_>, data: Form<NewSession<'_>>) -> Flash<Redirect> {
+    let error_redirect = Redirect::to(uri!(index(_, _)));
 
     if !repo::is_cloned() {
-        return Flash::error(error_redirect, "Repo not cloned");
+        return audited(&host_user.0, Action::StartSession, "", error_redirect, Err("Repo not cloned".to_string()));
     }
 
-    let mut sessions = sessions.lock().await;
-    let session = match Session::new(Some(data.password.to_string())).await {
+    let settings = match settings::read_settings().await {
         Ok(s) => s,
-        Err(e) => { return Flash::error(error_redirect, format!("Failed to start session: {e}")); },
+        Err(e) => return audited(&host_user.0, Action::StartSession, "", error_redirect, Err(format!("Failed to read settings: {e}"))),
+    };
+    let base = match data.base.trim() {
+        "" => settings.repo.branch,
+        base => base.to_string(),
     };
-    let redirect = Redirect::to(uri!(session_page(session.id)));
-    sessions.push(session);
 
-    Flash::success(redirect, "New session started")
+    let mut details = format!("base: {base}");
+    if let Some(parent_id) = data.parent {
+        details.push_str(&format!(", parent: {parent_id}"));
+    }
+
+    // Starting takes a while, so the sessions aren't held meanwhile
+    let (used_ports, parent) = {
+        let mut sessions = sessions.lock().await;
+        let used_ports: Vec<_> = sessions.iter_mut()
+            .filter_map(|s| s.check_is_running().unwrap_or(true).then_some(s.port))
+            .collect();
+        let parent = match data.parent {
+            Some(parent_id) => match sessions.iter().find(|s| s.id == parent_id).map(Session::parent_patch) {
+                Some(Ok(parent)) => Some(parent),
+                Some(Err(e)) => return audited(&host_user.0, Action::StartSession, &details, error_redirect, Err(format!("Failed to start session: {e}"))),
+                None => return audited(&host_user.0, Action::StartSession, &details, error_redirect, Err("Parent session not found".to_string())),
+            },
+            None => None,
+        };
+        (used_ports, parent)
+    };
+
+    let password = match data.password.trim() {
+        "" => sessions::generate_password(),
+        password => password.to_string(),
+    };
+    if let Err(e) = launcher::check_password(settings.launcher, &password) {
+        return audited(&host_user.0, Action::StartSession, &details, error_redirect, Err(format!("Failed to start session: {e}")));
+    }
+    let limits = SessionLimits {
+        max_hours: data.max_hours,
+        idle_minutes: data.idle_minutes,
+    };
+    let session_details = SessionDetails::new(data.title, data.description, data.tags);
+    let session = match Session::new(&host_user.0.name, Some(password), &base, session_details, limits, parent, &used_ports).await {
+        Ok(s) => s,
+        Err(e) => {
+            return audited(&host_user.0, Action::StartSession, &details, error_redirect, Err(format!("Failed to start session: {e}")));
+        },
+    };
+    let id = session.id;
+    sessions.lock().await.push(session);
+
+    audited(&host_user.0, Action::StartSession, &format!("session: {id}, {details}"), Redirect::to(uri!(session_page(id))),
+            Ok("New session started".to_string()))
 }
 
 #[get("/sessions/<id>")]
-async fn session_page(id: Uuid, user: Option<User>, flash: Option<FlashMessage<'_>>, sessions: SessionsState<'_>) -> Option<Template> {
+async fn session_page(id: Uuid, user: Option<User>, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, request_host: Option<&Host<'_>>,
+                      sessions: SessionsState<'_>) -> Option<Template> {
+    let public_host = settings::read_settings().await.map(|s| s.public_host).unwrap_or_default();
+    let join_host = match (public_host.is_empty(), request_host) {
+        (true, Some(host)) => host.domain().to_string(),
+        _ => public_host,
+    };
+
     let sessions = sessions.lock().await;
     let session = sessions.iter().find(|s| s.id == id)?;
+    let password = if has_role(&user, Role::Mapper) { session.password() } else { None };
+    let address = format!("{join_host}:{}", session.port);
+    // Everything the Connect to Server dialog of Enigma asks for, besides the user name
+    let connection = password.map(|password| format!("Server: {address}\nPassword: {password}"));
 
     Some(Template::render("session", context! {
         logged_in: user.is_some(),
-        admin: user.filter(|v| {v.0 == env::var("ADMIN_SESSION_ID").unwrap_or_default()}).is_some(),
+        admin: has_role(&user, Role::Admin),
+        host: has_role(&user, Role::SessionHost),
         msg: flash,
-        session: session
+        session: session,
+        description: markdown::to_html(&session.details.description),
+        password: password,
+        snapshots: session.snapshots().unwrap_or_else(|e| {
+            eprintln!("Failed to list the snapshots of session {id}: {e}");
+            vec![]
+        }),
+        deadline: session.deadline(),
+        collaborators: session.collaborators().unwrap_or_else(|e| {
+            eprintln!("Failed to read the collaborators of session {id}: {e}");
+            vec![]
+        }),
+        join_host: &join_host,
+        address: address,
+        connection: connection,
+        merge_branch: merge_branch_name(),
+        csrf_token: csrf.0,
     }))
 }
 
+/// Downloads need a login or an API token, every user has at least the viewer role
 #[get("/sessions/<id>/patch")]
-async fn session_patch(id: Uuid, sessions: SessionsState<'_>) -> Option<NamedFile> {
+async fn session_patch(id: Uuid, _user: User, sessions: SessionsState<'_>) -> Option<NamedFile> {
     let sessions = sessions.lock().await;
     let session = sessions.iter().find(|s| s.id == id)?;
 
     let file_path = session.get_patch_file();
     let file_path = file_path.as_path();
     if file_path.exists() {
-        if let Ok(file) = NamedFile::open(file_path).await {
-            Some(file)
-        } else {
-            None
-        }
+        NamedFile::open(file_path).await.ok()
     } else {
         None
     }
 }
 
-#[get("/sessions/<id>/log")]
-async fn session_log(id: Uuid, _admin_user: AdminUser) -> &'static str {
-    // TODO
-    "Session log goes here"
+#[get("/sessions/<id>/patch/mbox")]
+async fn session_patch_mbox(id: Uuid, _user: User, sessions: SessionsState<'_>) -> Option<Download<(ContentType, Vec<u8>)>> {
+    let sessions = sessions.lock().await;
+    let session = sessions.iter().find(|s| s.id == id)?;
+
+    let mbox = session.mbox_patch().await.map_err(|e| eprintln!("Failed to format the patch of session {id}: {e}")).ok()?;
+    Some(Download::new((ContentType::new("application", "mbox"), mbox), &format!("{id}.mbox")))
+}
+
+#[get("/sessions/<id>/mappings.zip")]
+async fn session_mappings_zip(id: Uuid, _user: User, sessions: SessionsState<'_>) -> Option<Download<(ContentType, Vec<u8>)>> {
+    let sessions = sessions.lock().await;
+    let session = sessions.iter().find(|s| s.id == id)?;
+
+    let zip = session.mappings_zip().map_err(|e| eprintln!("Failed to zip the mappings of session {id}: {e}")).ok()?;
+    Some(Download::new((ContentType::ZIP, zip), &format!("{id}-mappings.zip")))
+}
+
+#[get("/sessions/<id>/snapshots/<name>")]
+async fn session_snapshot(id: Uuid, name: &str, _user: User, sessions: SessionsState<'_>) -> Option<NamedFile> {
+    let sessions = sessions.lock().await;
+    let session = sessions.iter().find(|s| s.id == id)?;
+
+    let file_path = session.get_snapshot_file(name).ok()??;
+    NamedFile::open(file_path).await.ok()
+}
+
+#[get("/sessions/<id>/log?<stream>")]
+async fn session_log(id: Uuid, _host_user: HostUser, stream: Option<LogStream>, sessions: SessionsState<'_>) -> Option<Template> {
+    let mut sessions = sessions.lock().await;
+    let session = sessions.iter_mut().find(|s| s.id == id)?;
+    let running = session.check_is_running().unwrap_or_else(|e| {
+        eprintln!("Failed to check if session {id} is running: {e}");
+        session.is_running()
+    });
+
+    Some(Template::render("session_log", context! {
+        id: id,
+        stream: stream.unwrap_or_default(),
+        streams: LogStream::ALL,
+        running: running,
+    }))
+}
+
+/// Sends the end of the log, then each new line as it's written, until the session stops
+#[get("/sessions/<id>/log/events?<stream>")]
+async fn session_log_events(id: Uuid, _host_user: HostUser, stream: Option<LogStream>, sessions: SessionsState<'_>,
+                            mut shutdown: Shutdown) -> Option<EventStream![]> {
+    let path = {
+        let sessions = sessions.lock().await;
+        sessions.iter().find(|s| s.id == id)?.get_log_file(stream.unwrap_or_default())
+    };
+    let sessions = sessions.inner().clone();
+
+    Some(EventStream! {
+        let mut tail = match LogTail::open(&path, LOG_BACKFILL_BYTES).await {
+            Ok(tail) => tail,
+            Err(e) => {
+                yield Event::data(format!("Failed to open the log: {e}")).event("error");
+                return;
+            }
+        };
+
+        loop {
+            match tail.next_lines().await {
+                Ok(Some(lines)) => yield Event::data(lines),
+                Ok(None) => {},
+                Err(e) => {
+                    yield Event::data(format!("Failed to read the log: {e}")).event("error");
+                    break;
+                }
+            }
+
+            let running = {
+                let mut sessions = sessions.lock().await;
+                match sessions.iter_mut().find(|s| s.id == id) {
+                    Some(session) => session.check_is_running().unwrap_or(false),
+                    None => false,
+                }
+            };
+            if !running {
+                // Pick up whatever was written right before the process exited
+                if let Ok(Some(lines)) = tail.next_lines().await {
+                    yield Event::data(lines);
+                }
+                if let Some(line) = tail.remainder() {
+                    yield Event::data(line);
+                }
+                yield Event::data("").event("end");
+                break;
+            }
+
+            select! {
+                _ = sleep(LOG_POLL_INTERVAL) => {},
+                _ = &mut shutdown => break,
+            }
+        }
+    })
+}
+
+#[get("/sessions/<id>/log/download?<stream>")]
+async fn session_log_download(id: Uuid, _host_user: HostUser, stream: LogStream, sessions: SessionsState<'_>)
+                              -> Option<Download<NamedFile>> {
+    let mut sessions = sessions.lock().await;
+    let session = sessions.iter_mut().find(|s| s.id == id)?;
+    // The full log is only offered once it's complete
+    if session.check_is_running().unwrap_or(true) {
+        return None;
+    }
+
+    let file = NamedFile::open(session.get_log_file(stream)).await.ok()?;
+    let file_name = file.path().file_name()?.to_string_lossy().to_string();
+    Some(Download::new(file, &format!("{id}-{file_name}")))
 }
 
 #[post("/sessions/<id>/finish")]
-async fn finish_session(id: Uuid, _admin_user: AdminUser, sessions: SessionsState<'_>) -> Flash<Redirect> {
+async fn finish_session(id: Uuid, host_user: HostUser, sessions: SessionsState<'_>) -> Flash<Redirect> {
     let redirect = Redirect::to(uri!(session_page(id)));
+    let details = format!("session: {id}");
+    if !sessions.lock().await.iter().any(|s| s.id == id) {
+        return audited(&host_user.0, Action::FinishSession, &details, Redirect::to(uri!(index(_, _))), Err("Session not found".to_string()));
+    }
+
+    let result = match sessions::finish(sessions, id).await {
+        Ok(_) => Ok("Session finished".to_string()),
+        Err(e) => Err(format!("Failed to end session: {e}"))
+    };
+    audited(&host_user.0, Action::FinishSession, &details, redirect, result)
+}
+
+/// The branch finished sessions are merged into, for showing to admins
+fn merge_branch_name() -> String {
+    match repo::current_branch() {
+        Ok(Some(branch)) => branch,
+        Ok(None) => "a detached HEAD".to_string(),
+        Err(e) => format!("an unknown branch ({e})"),
+    }
+}
+
+#[post("/sessions/<id>/merge")]
+async fn merge_session(id: Uuid, admin_user: AdminUser, sessions: SessionsState<'_>) -> Flash<Redirect> {
+    let redirect = Redirect::to(uri!(session_page(id)));
+    let branch = merge_branch_name();
+    let details = format!("session: {id}, branch: {branch}");
     let mut sessions = sessions.lock().await;
 
     if let Some(session) = sessions.iter_mut().find(|s| s.id == id) {
-        match session.finish().await {
-            Ok(_) => Flash::success(redirect, "Session finished"),
-            Err(e) => Flash::error(redirect, format!("Failed to end session: {e}"))
-        }
+        let result = match session.merge().await {
+            Ok(commit) => Ok(format!("Session merged into {branch} as {commit}")),
+            Err(e) => Err(format!("Failed to merge session: {e}"))
+        };
+        audited(&admin_user.0, Action::MergeSession, &details, redirect, result)
+    } else {
+        audited(&admin_user.0, Action::MergeSession, &details, Redirect::to(uri!(index(_, _))), Err("Session not found".to_string()))
+    }
+}
+
+#[post("/sessions/<id>/details", data = "<data>")]
+async fn edit_session_details(id: Uuid, host_user: HostUser, sessions: SessionsState<'_>, data: Form<EditSessionDetails<'_>>)
+                              -> Flash<Redirect> {
+    let redirect = Redirect::to(uri!(session_page(id)));
+    let details = format!("session: {id}");
+    let mut sessions = sessions.lock().await;
+
+    if let Some(session) = sessions.iter_mut().find(|s| s.id == id) {
+        let result = match session.set_details(SessionDetails::new(data.title, data.description, data.tags)) {
+            Ok(_) => Ok("Session details updated".to_string()),
+            Err(e) => Err(format!("Failed to update the session details: {e}"))
+        };
+        audited(&host_user.0, Action::EditSession, &details, redirect, result)
     } else {
-        Flash::error(Redirect::to(uri!(index)), "Session not found")
+        audited(&host_user.0, Action::EditSession, &details, Redirect::to(uri!(index(_, _))), Err("Session not found".to_string()))
+    }
+}
+
+#[get("/users")]
+async fn users_page(admin_user: AdminUser, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, users: UsersState<'_>,
+                    attempts: LoginAttemptsState<'_>) -> Template {
+    let blocked_attempts: Vec<_> = attempts.lock().await.blocked_attempts().cloned().collect();
+    let users = users.lock().await;
+    let accounts: Vec<_> = users.accounts().iter()
+        .map(|a| context! {
+            id: a.id,
+            name: &a.name,
+            role: a.role,
+            disabled: a.disabled,
+            created: a.created,
+            current: a.id == admin_user.0.id,
+            two_factor: a.has_two_factor(),
+        })
+        .collect();
+
+    let invites: Vec<_> = users.invites().iter()
+        .map(|i| context! {
+            id: i.id,
+            role: i.role,
+            created: i.created,
+            created_by: users.get(i.created_by).map(|a| a.name.as_str()),
+            expires: i.expires,
+            expired: i.is_expired(),
+            used_by: i.used_by.and_then(|id| users.get(id)).map(|a| a.name.as_str()),
+        })
+        .collect();
+
+    Template::render("users", context! {
+        logged_in: true,
+        admin: true,
+        msg: flash,
+        users: accounts,
+        invites: invites,
+        roles: [Role::Viewer, Role::Mapper, Role::SessionHost, Role::Admin],
+        blocked_attempts: blocked_attempts,
+        csrf_token: csrf.0,
+    })
+}
+
+#[get("/audit?<filter..>")]
+async fn audit_page(_admin_user: AdminUser, csrf: CsrfToken, filter: Filter) -> Template {
+    let (entries, err) = match audit::search_log(&filter, AUDIT_LOG_LIMIT) {
+        Ok(e) => (e, None),
+        Err(e) => (vec![], Some(format!("Failed to read the audit log: {e}")))
+    };
+
+    Template::render("audit", context! {
+        logged_in: true,
+        admin: true,
+        csrf_token: csrf.0,
+        error: err,
+        entries: entries,
+        actions: Action::ALL,
+        filter: context! {
+            user: filter.user,
+            action: filter.action,
+            outcome: filter.outcome,
+        },
+        limit: AUDIT_LOG_LIMIT,
+    })
+}
+
+#[post("/users", data = "<new_user>")]
+async fn new_user_form(admin_user: AdminUser, users: UsersState<'_>, new_user: Form<NewUser<'_>>) -> Flash<Redirect> {
+    let redirect = Redirect::to(uri!(users_page));
+    let details = format!("user: {}, role: {}", new_user.name.trim(), new_user.role.as_str());
+
+    // Hashing is slow on purpose, so the users aren't held meanwhile
+    let password_hash = password::hash_new(new_user.password).await.map_err(|e| e.to_string());
+    let created = match password_hash {
+        Ok(hash) => users.lock().await.create(new_user.name, hash, new_user.role).map_err(|e| e.to_string()),
+        Err(e) => Err(e),
+    };
+    let result = match created {
+        Ok(_) => Ok(format!("Created user '{}'", new_user.name.trim())),
+        Err(e) => Err(format!("Failed to create user: {e}"))
+    };
+    audited(&admin_user.0, Action::CreateUser, &details, redirect, result)
+}
+
+/// Update another user's account, logging them out everywhere if `logins` is given
+async fn update_user<T: FnOnce(&mut UserStore) -> Result<(), Box<dyn Error>>>(admin_user: AdminUser, id: Uuid, users: UsersState<'_>, logins: Option<LoginsState<'_>>,
+                                                                             action: Action, updater: T, msg: &str) -> Flash<Redirect> {
+    let redirect = Redirect::to(uri!(users_page));
+    let mut users = users.lock().await;
+    let details = match users.get(id) {
+        Some(account) => format!("user: {}", account.name),
+        None => format!("user: {id}"),
+    };
+    if admin_user.0.id == id {
+        return audited(&admin_user.0, action, &details, redirect, Err("You can't modify your own account".to_string()));
+    }
+
+    let mut logins = match logins {
+        Some(logins) => Some(logins.lock().await),
+        None => None,
+    };
+    let result = updater(&mut users)
+        .and_then(|_| logins.as_mut().map_or(Ok(()), |l| l.revoke_all(id)));
+    let result = match result {
+        Ok(_) => Ok(msg.to_string()),
+        Err(e) => Err(format!("Failed to update user: {e}"))
+    };
+    audited(&admin_user.0, action, &details, redirect, result)
+}
+
+#[post("/users/<id>/disable")]
+async fn disable_user(id: Uuid, admin_user: AdminUser, users: UsersState<'_>, logins: LoginsState<'_>) -> Flash<Redirect> {
+    update_user(admin_user, id, users, Some(logins), Action::UpdateUser, |u| u.set_disabled(id, true), "User disabled").await
+}
+
+#[post("/users/<id>/enable")]
+async fn enable_user(id: Uuid, admin_user: AdminUser, users: UsersState<'_>) -> Flash<Redirect> {
+    update_user(admin_user, id, users, None, Action::UpdateUser, |u| u.set_disabled(id, false), "User enabled").await
+}
+
+#[post("/users/<id>/role", data = "<data>")]
+async fn set_user_role(id: Uuid, admin_user: AdminUser, users: UsersState<'_>, logins: LoginsState<'_>, data: Form<RoleData>) -> Flash<Redirect> {
+    // Logging out makes the user go through the second factor again, if the new role requires it
+    let msg = format!("User role set to {}", data.role.as_str());
+    update_user(admin_user, id, users, Some(logins), Action::UpdateUser, |u| u.set_role(id, data.role), &msg).await
+}
+
+#[post("/users/<id>/two-factor/reset")]
+async fn reset_user_two_factor(id: Uuid, admin_user: AdminUser, users: UsersState<'_>, logins: LoginsState<'_>) -> Flash<Redirect> {
+    update_user(admin_user, id, users, Some(logins), Action::UpdateUser, |u| u.reset_two_factor(id), "Two-factor authentication reset").await
+}
+
+#[post("/users/<id>/delete")]
+async fn delete_user(id: Uuid, admin_user: AdminUser, users: UsersState<'_>, logins: LoginsState<'_>) -> Flash<Redirect> {
+    update_user(admin_user, id, users, Some(logins), Action::DeleteUser, |u| u.delete(id), "User deleted").await
+}
+
+#[post("/invites", data = "<data>")]
+async fn new_invite_form(admin_user: AdminUser, csrf: CsrfToken, users: UsersState<'_>, data: Form<NewInvite>) -> Result<Template, Flash<Redirect>> {
+    let redirect = Redirect::to(uri!(users_page));
+    let details = format!("role: {}, valid for {} hours", data.role.as_str(), data.valid_hours);
+    if data.valid_hours == 0 {
+        return Err(audited(&admin_user.0, Action::CreateInvite, &details, redirect, Err("Invites must be valid for at least one hour".to_string())));
+    }
+
+    let mut users = users.lock().await;
+    match users.create_invite(data.role, admin_user.0.id, Duration::hours(data.valid_hours.into())) {
+        Ok(token) => {
+            // The token itself must not end up in the log, nor in a flash cookie
+            audit::record(&admin_user.0, Action::CreateInvite, &details, &Ok("Invite created".to_string()));
+            Ok(Template::render("invite", context! {
+                logged_in: true,
+                admin: true,
+                role: data.role,
+                link: uri!(register_page(Some(token))).to_string(),
+                csrf_token: csrf.0,
+            }))
+        },
+        Err(e) => Err(audited(&admin_user.0, Action::CreateInvite, &details, redirect, Err(format!("Failed to create invite: {e}"))))
+    }
+}
+
+#[post("/invites/<id>/delete")]
+async fn delete_invite(id: Uuid, admin_user: AdminUser, users: UsersState<'_>) -> Flash<Redirect> {
+    let redirect = Redirect::to(uri!(users_page));
+    let mut users = users.lock().await;
+
+    let result = match users.delete_invite(id) {
+        Ok(_) => Ok("Invite deleted".to_string()),
+        Err(e) => Err(format!("Failed to delete invite: {e}"))
+    };
+    audited(&admin_user.0, Action::DeleteInvite, &format!("invite: {id}"), redirect, result)
+}
+
+#[get("/register?<token>")]
+async fn register_page(token: Option<String>, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, users: UsersState<'_>) -> Template {
+    let role = match &token {
+        Some(token) => users.lock().await.find_invite(token).map(|i| i.role),
+        None => None,
+    };
+
+    Template::render("register", context! {
+        logged_in: false,
+        msg: flash,
+        invalid: token.is_some() && role.is_none(),
+        token: token,
+        role: role,
+        csrf_token: csrf.0,
+    })
+}
+
+#[post("/register", data = "<registration>")]
+async fn register_form(_csrf: VerifiedCsrf, client: Client, cookies: &CookieJar<'_>, users: UsersState<'_>,
+                       logins: LoginsState<'_>, registration: Form<Registration<'_>>) -> Flash<Redirect> {
+    let error_redirect = Redirect::to(uri!(register_page(Some(registration.token))));
+    if users.lock().await.find_invite(registration.token).is_none() {
+        return Flash::error(error_redirect, "Failed to register: Invalid or expired invite");
+    }
+
+    // Hashing is slow on purpose, so the users aren't held meanwhile
+    let password_hash = match password::hash_new(registration.password).await {
+        Ok(hash) => hash,
+        Err(e) => return Flash::error(error_redirect, format!("Failed to register: {e}")),
+    };
+
+    let mut users = users.lock().await;
+    let mut logins = logins.lock().await;
+    match users.register(registration.token, registration.name, password_hash) {
+        Ok(id) => match users.get(id) {
+            Some(account) if account.requires_two_factor() => start_login(cookies, &mut logins, client, account),
+            _ => finish_login(cookies, &mut logins, client, id, "Account created"),
+        },
+        Err(e) => Flash::error(error_redirect, format!("Failed to register: {e}"))
+    }
+}
+
+#[get("/account")]
+async fn account_page(user: User, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, users: UsersState<'_>, logins: LoginsState<'_>) -> Option<Template> {
+    let users = users.lock().await;
+    let account = users.get(user.id)?;
+    let logins = logins.lock().await;
+
+    Some(Template::render("account", context! {
+        logged_in: true,
+        admin: user.has_role(Role::Admin),
+        msg: flash,
+        name: &account.name,
+        role: account.role,
+        tokens: account.tokens(),
+        two_factor: account.has_two_factor(),
+        two_factor_required: account.role == Role::Admin,
+        recovery_codes_left: account.recovery_codes_left(),
+        logins: logins.for_user(user.id),
+        current_login: user.login,
+        csrf_token: csrf.0,
+    }))
+}
+
+#[post("/account/password", data = "<data>")]
+async fn change_password(user: User, client: Client, cookies: &CookieJar<'_>, users: UsersState<'_>, logins: LoginsState<'_>,
+                         data: Form<PasswordChange<'_>>) -> Flash<Redirect> {
+    let redirect = Redirect::to(uri!(account_page));
+
+    // Hashing is slow on purpose, so the passwords are checked and hashed without holding the users
+    let old_hash = match users.lock().await.password_hash(&user.name) {
+        Some((_, hash)) => hash,
+        None => return Flash::error(redirect, "Failed to change password: User not found"),
+    };
+    if password::verify_account_async(data.current_password, Some(old_hash.clone())).await == Verification::Invalid {
+        return Flash::error(redirect, "Failed to change password: The current password is wrong");
+    }
+    let new_hash = match password::hash_new(data.new_password).await {
+        Ok(hash) => hash,
+        Err(e) => return Flash::error(redirect, format!("Failed to change password: {e}")),
+    };
+
+    if let Err(e) = users.lock().await.change_password(user.id, &old_hash, new_hash) {
+        return Flash::error(redirect, format!("Failed to change password: {e}"));
+    }
+
+    // Log out everywhere else, but keep this browser logged in
+    let mut logins = logins.lock().await;
+    let result = logins.revoke_all(user.id)
+        .and_then(|_| if user.login.is_some() { auth::log_in(cookies, &mut logins, client, user.id).map(|_| ()) } else { Ok(()) });
+    match result {
+        Ok(_) => Flash::success(redirect, "Password changed, all other logins were logged out"),
+        Err(e) => Flash::error(redirect, format!("Password changed, but failed to revoke the logins: {e}"))
+    }
+}
+
+#[post("/account/logins/<id>/revoke")]
+async fn revoke_login(id: Uuid, user: User, logins: LoginsState<'_>) -> Flash<Redirect> {
+    let redirect = Redirect::to(uri!(account_page));
+    let mut logins = logins.lock().await;
+
+    match logins.revoke(user.id, id) {
+        Ok(_) if user.login == Some(id) => Flash::success(Redirect::to(uri!(login_page)), "Logged out"),
+        Ok(_) => Flash::success(redirect, "Login revoked"),
+        Err(e) => Flash::error(redirect, format!("Failed to revoke login: {e}"))
+    }
+}
+
+#[post("/account/logins/revoke")]
+async fn revoke_all_logins(user: User, logins: LoginsState<'_>) -> Flash<Redirect> {
+    match logins.lock().await.revoke_all(user.id) {
+        Ok(_) => Flash::success(Redirect::to(uri!(login_page)), "Logged out everywhere"),
+        Err(e) => Flash::error(Redirect::to(uri!(account_page)), format!("Failed to revoke logins: {e}"))
+    }
+}
+
+#[get("/account/two-factor")]
+async fn account_two_factor_page(user: User, flash: Option<FlashMessage<'_>>, csrf: CsrfToken, users: UsersState<'_>) -> Option<Template> {
+    let mut users = users.lock().await;
+    two_factor_setup(&mut users, user.id, "/account/two-factor", flash, csrf, true)
+}
+
+#[post("/account/two-factor", data = "<data>")]
+async fn account_two_factor_form(user: User, csrf: CsrfToken, users: UsersState<'_>, data: Form<TwoFactorCode<'_>>) -> Result<Template, Flash<Redirect>> {
+    let mut users = users.lock().await;
+
+    match users.confirm_two_factor_setup(user.id, data.code) {
+        Ok(codes) => Ok(recovery_codes_page(codes, user.has_role(Role::Admin), csrf.0)),
+        Err(e) => Err(Flash::error(Redirect::to(uri!(account_two_factor_page)), format!("Failed to enable two-factor authentication: {e}")))
+    }
+}
+
+#[post("/account/two-factor/recovery-codes")]
+async fn regenerate_recovery_codes(user: User, csrf: CsrfToken, users: UsersState<'_>) -> Result<Template, Flash<Redirect>> {
+    let mut users = users.lock().await;
+
+    match users.regenerate_recovery_codes(user.id) {
+        Ok(codes) => Ok(recovery_codes_page(codes, user.has_role(Role::Admin), csrf.0)),
+        Err(e) => Err(Flash::error(Redirect::to(uri!(account_page)), format!("Failed to generate recovery codes: {e}")))
+    }
+}
+
+#[post("/account/two-factor/disable")]
+async fn disable_two_factor(user: User, users: UsersState<'_>) -> Flash<Redirect> {
+    let redirect = Redirect::to(uri!(account_page));
+    if user.has_role(Role::Admin) {
+        return Flash::error(redirect, "Two-factor authentication is required for admins");
+    }
+
+    let mut users = users.lock().await;
+    match users.reset_two_factor(user.id) {
+        Ok(_) => Flash::success(redirect, "Two-factor authentication disabled"),
+        Err(e) => Flash::error(redirect, format!("Failed to disable two-factor authentication: {e}"))
+    }
+}
+
+#[get("/account", rank = 2)]
+fn account_redirect() -> Redirect {
+    Redirect::to(uri!(login))
+}
+
+#[post("/account/tokens", data = "<data>")]
+async fn new_token_form(user: User, csrf: CsrfToken, users: UsersState<'_>, data: Form<NewToken<'_>>) -> Result<Template, Flash<Redirect>> {
+    let mut users = users.lock().await;
+
+    match users.create_token(user.id, data.name) {
+        Ok(token) => Ok(Template::render("api_token", context! {
+            logged_in: true,
+            admin: user.has_role(Role::Admin),
+            name: data.name.trim(),
+            token: token,
+            csrf_token: csrf.0,
+        })),
+        Err(e) => Err(Flash::error(Redirect::to(uri!(account_page)), format!("Failed to create token: {e}")))
+    }
+}
+
+#[post("/account/tokens/<id>/revoke")]
+async fn revoke_token(id: Uuid, user: User, users: UsersState<'_>) -> Flash<Redirect> {
+    let redirect = Redirect::to(uri!(account_page));
+    let mut users = users.lock().await;
+
+    match users.revoke_token(user.id, id) {
+        Ok(_) => Flash::success(redirect, "Token revoked"),
+        Err(e) => Flash::error(redirect, format!("Failed to revoke token: {e}"))
     }
 }
 
 pub fn routes() -> Vec<Route> {
     routes![index,
         login, login_page, login_form, logout,
-        settings_page, post_settings, post_repo_settings, settings_unauthorized, settings_redirect,
-        clone_repo, fetch, pull, checkout,
-        new_session_page, new_session_form, session_page, session_patch, session_log, finish_session]
+        two_factor_page, two_factor_form, two_factor_setup_page, two_factor_setup_form, two_factor_redirect, two_factor_setup_redirect,
+        settings_page, post_settings, post_repo_settings, post_remote_settings, settings_unauthorized, settings_redirect,
+        clone_repo, fetch, pull, push, checkout,
+        new_session_page, new_session_form, session_page, session_patch, session_patch_mbox, session_mappings_zip, session_snapshot, session_log, session_log_events,
+        session_log_download, finish_session, merge_session, edit_session_details,
+        users_page, new_user_form, audit_page, disable_user, enable_user, set_user_role, delete_user,
+        new_invite_form, delete_invite, register_page, register_form,
+        account_page, account_redirect, change_password, revoke_login, revoke_all_logins, new_token_form, revoke_token,
+        account_two_factor_page, account_two_factor_form, regenerate_recovery_codes, disable_two_factor,
+        reset_user_two_factor]
 }
@@ -1,23 +1,55 @@
 use std::error::Error;
 use std::fs;
 use std::fs::File;
-use std::io::Result as IoResult;
+use std::io::{Cursor, ErrorKind, Result as IoResult, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, TcpListener};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Child, ExitStatus};
 use std::result::Result as StdResult;
 use std::string::ToString;
+use std::time::{Duration, Instant};
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration as ChronoDuration, NaiveDateTime, Utc};
+use git2::Repository;
 use serde::{Deserialize, Serialize, Serializer};
 use uuid::Uuid;
+use zip::write::SimpleFileOptions;
+use rocket::tokio::sync::Mutex;
+use rocket::tokio::task;
+use rocket::tokio::time::sleep;
+use zip::ZipWriter;
 
-use crate::{repo, util};
-use crate::settings::{read_settings, Settings};
-use crate::util::{some_or_throw};
+use crate::{launcher, process, repo, util};
+use crate::launcher::LaunchParams;
+use crate::process::ProcessInfo;
+use crate::settings::{DEFAULT_ENIGMA_PORT, read_settings, Settings};
+use crate::util::{some_or_throw, throw};
 
 const DIR: &str = "data/sessions";
+const SESSION_FILE: &str = "session.toml";
 const PID_FILE: &str = "session.pid";
 const PATCH_FILE: &str = "session.patch";
+const PASSWORD_FILE: &str = "session.password";
+const STDOUT_FILE: &str = "stdout.log";
+const STDERR_FILE: &str = "stderr.log";
+const PASSWORD_LENGTH: usize = 20;
+const SNAPSHOTS_DIR: &str = "snapshots";
+const SNAPSHOT_EXTENSION: &str = "patch";
+const SNAPSHOT_DATE_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+/// Ports tried after the configured one, when running several sessions
+const MAX_PORT_ATTEMPTS: u16 = 100;
+/// Written by the Enigma server to its log when a user joins, i.e. `[server] alice logged in with IP ...`
+const LOG_PREFIX: &str = "[server] ";
+const LOGIN_MARKER: &str = " logged in";
+const MERGE_DATE_FORMAT: &str = "%Y-%m-%d %H:%M UTC";
+/// How long before finishing automatically a session shows a warning
+const LIMIT_WARNING_MINUTES: i64 = 15;
+/// How long to wait for a new Enigma server to listen on its port, loading large jars can take a while
+const SERVER_START_TIMEOUT: Duration = Duration::from_secs(30);
+const SERVER_START_POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// Written by Java to the error output when the port is taken
+const ADDRESS_IN_USE: &str = "Address already in use";
 
 type Result<T> = StdResult<T, Box<dyn Error>>;
 
@@ -29,10 +61,227 @@ pub struct Session {
     pub rev: String,
     #[serde(default)]
     pub jar_info: JarInfo,
-    password: Option<String>, // TODO: Serialize only when writing the session.toml file
+    /// The branch or revision the session was started from
+    #[serde(default)]
+    pub base: String,
+    #[serde(default)]
+    pub details: SessionDetails,
+    /// The session whose patch was applied before starting this one
+    #[serde(default)]
+    pub parent: Option<Uuid>,
+    /// Name of the user who started the session, unknown for older sessions
+    #[serde(default)]
+    pub host: String,
+    /// The commit the patch was merged into the branch as, if it was
+    #[serde(default)]
+    pub merge_commit: Option<String>,
+    /// Port of the Enigma server
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// Working tree of the session, older sessions all used the main repository
+    #[serde(default = "default_worktree")]
+    worktree: PathBuf,
+    // Kept in its own file so it doesn't end up in the html templates, older sessions stored it here
+    #[serde(default, skip_serializing)]
+    password: Option<String>,
+    #[serde(default)]
+    pub state: SessionState,
+    /// Every state the session went through, oldest first
+    #[serde(default)]
+    pub transitions: Vec<Transition>,
+    /// How the Enigma server exited, if it was started by this process
+    #[serde(default)]
+    pub exit_info: Option<ExitInfo>,
+    /// Missing for sessions started before there were limits, they get the default ones when loaded
+    #[serde(default)]
+    pub limits: Option<SessionLimits>,
+    /// Last time a user was connected or the mappings changed, the start of the session if never
+    #[serde(default)]
+    last_activity: Option<DateTime<Utc>>,
+    /// Hash of the mappings diff at the last activity check
+    #[serde(skip)]
+    last_patch_hash: Option<String>,
+    /// Tells the Enigma server apart from other processes reusing its pid after a restart
+    #[serde(default)]
+    process_info: Option<ProcessInfo>,
     // Serialize as `running: bool` for use in the html templates
     #[serde(skip_deserializing, rename(serialize = "running"), serialize_with = "serialize_running")]
     pid: Option<u32>,
+    /// Only available for sessions started since the last restart
+    #[serde(skip)]
+    child: Option<Child>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionState {
+    /// Preparing the working tree and launching the Enigma server
+    #[default]
+    Starting,
+    Running,
+    /// Stopping the Enigma server and saving the mappings diff
+    Finishing,
+    Finished,
+    /// The Enigma server exited on its own
+    Crashed,
+    /// Interrupted while starting or finishing, or left behind by an older version
+    Abandoned,
+    /// The Enigma server couldn't be identified after a restart, so it's left alone
+    Orphaned,
+}
+
+impl SessionState {
+    /// Whether the session is still in progress, as opposed to over one way or another
+    pub fn is_active(&self) -> bool {
+        matches!(self, SessionState::Starting | SessionState::Running | SessionState::Finishing)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Transition {
+    pub state: SessionState,
+    pub date: DateTime<Utc>,
+}
+
+/// What a session is about, set by its host and editable at any time
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionDetails {
+    pub title: String,
+    /// Markdown, usually the goal of the session
+    pub description: String,
+    pub tags: Vec<String>,
+}
+
+impl SessionDetails {
+    /// Build the details from form input, with the tags separated by commas
+    pub fn new(title: &str, description: &str, tags: &str) -> SessionDetails {
+        let mut unique_tags: Vec<String> = vec![];
+        for tag in tags.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            if !unique_tags.iter().any(|t| t.eq_ignore_ascii_case(tag)) {
+                unique_tags.push(tag.to_string());
+            }
+        }
+
+        SessionDetails {
+            title: title.trim().to_string(),
+            description: description.trim().to_string(),
+            tags: unique_tags,
+        }
+    }
+}
+
+/// Criteria to search the sessions with, all of them optional and case-insensitive
+#[derive(Debug, Default, FromForm)]
+pub struct Filter {
+    /// Searched in the id, title and description
+    pub text: Option<String>,
+    pub tag: Option<String>,
+}
+
+impl Filter {
+    pub fn matches(&self, session: &Session) -> bool {
+        let details = &session.details;
+        let text = self.text.as_deref().map(str::trim).filter(|t| !t.is_empty()).map(str::to_lowercase);
+        let tag = self.tag.as_deref().map(str::trim).filter(|t| !t.is_empty());
+
+        text.is_none_or(|text| [session.id.to_string().as_str(), &details.title, &details.description].iter()
+                .any(|field| field.to_lowercase().contains(&text)))
+            && tag.is_none_or(|tag| details.tags.iter().any(|t| t.eq_ignore_ascii_case(tag)))
+    }
+}
+
+/// When a running session is finished automatically, 0 meaning no limit
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct SessionLimits {
+    pub max_hours: u16,
+    /// Minutes without connected users nor changes to the mappings
+    pub idle_minutes: u16,
+}
+
+impl SessionLimits {
+    /// The default limits of new sessions
+    pub fn from_settings(settings: &Settings) -> SessionLimits {
+        SessionLimits {
+            max_hours: settings.max_session_hours,
+            idle_minutes: settings.session_idle_minutes,
+        }
+    }
+}
+
+/// Whether users were connected to a running session, and the state of its mappings, at some point
+#[derive(Debug)]
+pub struct Activity {
+    connected: bool,
+    patch_hash: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitReason {
+    MaxDuration,
+    Idle,
+}
+
+/// The next time a session will be finished automatically
+#[derive(Debug, Serialize)]
+pub struct Deadline {
+    pub date: DateTime<Utc>,
+    pub reason: LimitReason,
+    /// Whether it's close enough to warn the users
+    pub imminent: bool,
+}
+
+/// The patch of a finished session, applied before starting a new one
+#[derive(Debug)]
+pub struct ParentPatch {
+    id: Uuid,
+    patch: Vec<u8>,
+}
+
+impl ParentPatch {
+    /// Fails with the conflicting files if it doesn't apply cleanly
+    fn apply_to(&self, repo: &Repository) -> Result<()> {
+        if let Err(conflicts) = repo::apply_patch(repo, &self.patch)? {
+            throw!("The patch of session {} conflicts with the current revision in: {}", self.id, conflicts.join(", "))
+        }
+
+        Ok(())
+    }
+}
+
+/// A periodic copy of the mappings diff of a session
+#[derive(Debug, Serialize)]
+pub struct Snapshot {
+    pub name: String,
+    pub date: DateTime<Utc>,
+    pub size: u64,
+}
+
+/// An output stream of the Enigma server, logged to a file in the session directory
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, FromFormField)]
+#[serde(rename_all = "snake_case")]
+pub enum LogStream {
+    #[default]
+    Stdout,
+    Stderr,
+}
+
+impl LogStream {
+    pub const ALL: [LogStream; 2] = [LogStream::Stdout, LogStream::Stderr];
+
+    fn file_name(&self) -> &'static str {
+        match self {
+            LogStream::Stdout => STDOUT_FILE,
+            LogStream::Stderr => STDERR_FILE,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExitInfo {
+    pub code: Option<i32>,
+    pub signal: Option<i32>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -46,25 +295,154 @@ impl Session {
         self.pid.is_some()
     }
 
-    pub fn check_is_running(&mut self) -> IoResult<bool> {
+    pub fn check_is_running(&mut self) -> Result<bool> {
         self.check_process()?;
         Ok(self.is_running())
     }
 
-    fn check_process(&mut self) -> IoResult<()> {
-        if let Some(pid) = self.pid {
-            let status = Command::new("kill")
-                .arg("-0")
-                .arg(pid.to_string())
-                .status()?;
-            if !status.success() {
-                self.invalidate_pid()?
+    fn check_process(&mut self) -> Result<()> {
+        let Some(pid) = self.pid else {
+            return Ok(());
+        };
+
+        if let process::State::Exited(status) = process::poll(pid, self.child.as_mut())? {
+            self.exited(status)?;
+
+            if self.state == SessionState::Running {
+                self.transition(SessionState::Crashed)?;
             }
+        } else if self.child.is_none() && !self.owns_process(pid)? {
+            eprintln!("Process {pid} doesn't look like the Enigma server of session {}, leaving it alone", self.id);
+            self.invalidate_pid()?;
+            self.transition(SessionState::Orphaned)?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether `pid` is still the process started for this session, and not another one that reused the pid
+    fn owns_process(&self, pid: u32) -> Result<bool> {
+        match &self.process_info {
+            Some(info) => Ok(ProcessInfo::read(pid)?.is_some_and(|current| info.matches(&current))),
+            // Started by an older version, there's no way to be sure
+            None => Ok(false),
+        }
+    }
+
+    /// When the session will be finished automatically because of its limits, if it's running
+    pub fn deadline(&self) -> Option<Deadline> {
+        if self.state != SessionState::Running {
+            return None;
+        }
+
+        let limits = self.limits.unwrap_or_default();
+        let max_duration = (limits.max_hours > 0).then(|| (
+            self.date + ChronoDuration::hours(limits.max_hours.into()),
+            LimitReason::MaxDuration,
+        ));
+        let idle = (limits.idle_minutes > 0).then(|| (
+            self.last_activity.unwrap_or(self.date) + ChronoDuration::minutes(limits.idle_minutes.into()),
+            LimitReason::Idle,
+        ));
+
+        max_duration.into_iter().chain(idle)
+            .min_by_key(|(date, _)| *date)
+            .map(|(date, reason)| Deadline {
+                date,
+                reason,
+                imminent: date - Utc::now() < ChronoDuration::minutes(LIMIT_WARNING_MINUTES),
+            })
+    }
+
+    /// Record activity if a user was connected to the Enigma server or the mappings changed since the last check
+    pub fn record_activity(&mut self, activity: Activity) -> Result<()> {
+        let changed = self.last_patch_hash.as_ref().is_some_and(|hash| *hash != activity.patch_hash);
+        self.last_patch_hash = Some(activity.patch_hash);
+
+        if activity.connected || changed {
+            self.last_activity = Some(Utc::now());
+            self.write()?;
         }
 
         Ok(())
     }
 
+    /// The working tree of the session, older sessions all used the main repository
+    pub fn worktree(&self) -> &Path {
+        &self.worktree
+    }
+
+    pub fn set_details(&mut self, details: SessionDetails) -> Result<()> {
+        self.details = details;
+        self.write()
+    }
+
+    fn transition(&mut self, state: SessionState) -> Result<()> {
+        self.state = state;
+        self.transitions.push(Transition {
+            state,
+            date: Utc::now(),
+        });
+
+        self.write()
+    }
+
+    /// Give a state to sessions that were interrupted by a restart, or created before states were recorded
+    fn recover_state(&mut self) -> Result<()> {
+        if self.transitions.is_empty() {
+            self.state = if self.is_running() {
+                SessionState::Running
+            } else if self.get_patch_file().exists() {
+                SessionState::Finished
+            } else {
+                SessionState::Abandoned
+            };
+            self.transitions.push(Transition {
+                state: self.state,
+                date: self.date,
+            });
+            return self.write();
+        }
+
+        match self.interrupted_state() {
+            Some(state) => self.transition(state),
+            None => Ok(()),
+        }
+    }
+
+    /// The state of a session that was interrupted by a restart while starting or finishing, if it was
+    fn interrupted_state(&self) -> Option<SessionState> {
+        match self.state {
+            SessionState::Starting | SessionState::Finishing => Some(SessionState::Abandoned),
+            _ => None,
+        }
+    }
+
+    /// Stop the Enigma server and remove the worktree of a session that was interrupted while starting
+    ///
+    /// It wasn't listed yet, so nobody could have joined it and there's nothing to save
+    async fn clean_up_start(&mut self) {
+        if self.pid.is_some() {
+            self.kill_server().await;
+            if let Err(e) = self.invalidate_pid() {
+                eprintln!("Failed to remove the pid file of session {}: {e}", self.id);
+            }
+        }
+
+        if self.worktree != Path::new(repo::DIR) {
+            if let Err(e) = repo::open_worktree(repo::DIR).and_then(|r| repo::remove_worktree(&r, &self.id.to_string())) {
+                eprintln!("Failed to remove the worktree of session {}: {e}", self.id);
+            }
+        }
+    }
+
+    fn exited(&mut self, status: Option<ExitStatus>) -> Result<()> {
+        self.exit_info = status.map(ExitInfo::from);
+        self.child = None;
+        self.invalidate_pid()?;
+        self.write()
+    }
+
     fn invalidate_pid(&mut self) -> IoResult<()> {
         self.pid = None;
 
@@ -83,6 +461,159 @@ impl Session {
         self.get_file(PATCH_FILE)
     }
 
+    pub fn get_log_file(&self, stream: LogStream) -> PathBuf {
+        self.get_file(stream.file_name())
+    }
+
+    /// The snapshots of the session, oldest first
+    pub fn snapshots(&self) -> IoResult<Vec<Snapshot>> {
+        list_snapshots(&self.get_file(SNAPSHOTS_DIR))
+    }
+
+    /// The file of the snapshot with the given name, if it exists
+    pub fn get_snapshot_file(&self, name: &str) -> IoResult<Option<PathBuf>> {
+        let exists = self.snapshots()?.iter().any(|s| s.name == name);
+        Ok(exists.then(|| self.get_file(SNAPSHOTS_DIR).join(name)))
+    }
+
+    /// The working tree and the snapshots directory of the session, to take a [`snapshot`] without holding on to it
+    pub fn snapshot_paths(&self) -> (PathBuf, PathBuf) {
+        (self.worktree.clone(), self.get_file(SNAPSHOTS_DIR))
+    }
+
+    /// The patch of this finished session, to start a new one from it
+    pub fn parent_patch(&self) -> Result<ParentPatch> {
+        Ok(ParentPatch {
+            id: self.id,
+            patch: self.read_patch()?,
+        })
+    }
+
+    /// The users who took part in the session: its host, then everyone who joined the Enigma server
+    pub fn collaborators(&self) -> IoResult<Vec<String>> {
+        let mut collaborators = vec![];
+        if !self.host.is_empty() {
+            collaborators.push(self.host.clone());
+        }
+
+        let log_file = self.get_log_file(LogStream::Stdout);
+        if log_file.exists() {
+            for name in parse_logins(&fs::read_to_string(log_file)?) {
+                if !collaborators.contains(&name) {
+                    collaborators.push(name);
+                }
+            }
+        }
+
+        Ok(collaborators)
+    }
+
+    fn merge_message(&self, template: &str) -> IoResult<String> {
+        let title = match self.details.title.as_str() {
+            "" => format!("Session {}", self.id),
+            title => title.to_string(),
+        };
+
+        Ok(template
+            .replace("{title}", &title)
+            .replace("{id}", &self.id.to_string())
+            .replace("{date}", &self.date.format(MERGE_DATE_FORMAT).to_string())
+            .replace("{base}", &self.base)
+            .replace("{rev}", &self.rev)
+            .replace("{collaborators}", &self.collaborators()?.join(", ")))
+    }
+
+    /// When the session was last finished, or started if it never was
+    fn finish_date(&self) -> DateTime<Utc> {
+        self.transitions.iter().rev()
+            .find(|t| t.state == SessionState::Finished)
+            .map_or(self.date, |t| t.date)
+    }
+
+    fn read_patch(&self) -> Result<Vec<u8>> {
+        let patch_file = self.get_patch_file();
+        if !patch_file.exists() {
+            throw!("Session {} has no patch, it must be finished first", self.id)
+        }
+
+        Ok(fs::read(patch_file)?)
+    }
+
+    /// The patch of this finished session as an email, so maintainers can apply it with `git am`
+    ///
+    /// The message is the one of the merge commits, listing the collaborators in the body if it doesn't already.
+    /// They're Enigma user names without an email, so they can't be `Co-authored-by` trailers
+    pub async fn mbox_patch(&self) -> Result<Vec<u8>> {
+        let settings = read_settings().await?;
+        let patch = self.read_patch()?;
+
+        let mut message = self.merge_message(&settings.merge_message_template)?.trim_end().to_string();
+        let collaborators = self.collaborators()?;
+        if !collaborators.is_empty() && !settings.merge_message_template.contains("{collaborators}") {
+            message.push_str(&format!("\n\nCollaborators: {}", collaborators.join(", ")));
+        }
+
+        let repo = repo::open_worktree(repo::DIR)?;
+        let author = repo::signature(&repo)?;
+        Ok(repo::format_patch(self.merge_commit.as_deref(), &author, self.finish_date(), &message, &patch))
+    }
+
+    /// A zip of the mapping files modified by this finished session, as they were at its end
+    pub fn mappings_zip(&self) -> Result<Vec<u8>> {
+        let patch = self.read_patch()?;
+        let repo = repo::open_worktree(repo::DIR)?;
+        let files = repo::patched_files(&repo, &self.rev, &patch)?;
+
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+        for (path, content) in files {
+            zip.start_file(path, SimpleFileOptions::default())?;
+            zip.write_all(&content)?;
+        }
+
+        Ok(zip.finish()?.into_inner())
+    }
+
+    /// Commit the patch of this finished session to the checked out branch of the main repository
+    ///
+    /// Returns the id of the new commit
+    pub async fn merge(&mut self) -> Result<String> {
+        if self.state != SessionState::Finished {
+            throw!("Only finished sessions can be merged")
+        }
+        if let Some(commit) = &self.merge_commit {
+            throw!("The session was already merged as {}", commit)
+        }
+
+        let settings = read_settings().await?;
+        let patch = self.read_patch()?;
+        let message = self.merge_message(&settings.merge_message_template)?;
+
+        let repo = repo::open_worktree(repo::DIR)?;
+        // Sessions started from a revision can go anywhere, but not ones started from another branch
+        if let Some(base) = repo::ref_branch(&repo, &self.base)? {
+            let target = repo::head_branch(&repo)?;
+            if target.as_deref() != Some(base.as_str()) {
+                throw!("The session was started from {}, but {} is checked out", base,
+                       target.as_deref().unwrap_or("a detached HEAD"))
+            }
+        }
+
+        let commit = match repo::commit_patch(&repo, &patch, &message)? {
+            Ok(oid) => oid.to_string(),
+            Err(conflicts) => throw!("The patch conflicts with the checked out branch in: {}", conflicts.join(", ")),
+        };
+
+        self.merge_commit = Some(commit.clone());
+        self.write()?;
+
+        Ok(commit)
+    }
+
+    /// The password needed to join the session, only to be shown to users allowed to join
+    pub fn password(&self) -> Option<&str> {
+        self.password.as_deref()
+    }
+
     fn deserialize<P: AsRef<Path>>(path: P) -> Result<Session> {
         let toml_str = fs::read_to_string(path)?;
         let s = toml::from_str(toml_str.as_str())?;
@@ -99,8 +630,12 @@ impl Session {
 
     pub fn read<P: AsRef<Path>>(path: P) -> Result<Session> {
         let path = path.as_ref();
-        let mut session = Self::deserialize(path.join("session.toml"))?;
+        let mut session = Self::deserialize(path.join(SESSION_FILE))?;
         session.pid = Self::read_pid(path.join(PID_FILE))?;
+        let password_file = path.join(PASSWORD_FILE);
+        if password_file.exists() {
+            session.password = Some(fs::read_to_string(password_file)?);
+        }
 
         Ok(session)
     }
@@ -117,87 +652,252 @@ impl Session {
     }
 
     fn write(&self) -> Result<()> {
-        Self::serialize(self.get_file("session.toml"), &self)
+        if let Some(password) = &self.password {
+            fs::write(self.get_file(PASSWORD_FILE), password)?;
+        }
+
+        Self::serialize(self.get_file(SESSION_FILE), self)
     }
 
-    pub async fn new(password: Option<String>) -> Result<Session> {
+    /// Start a new session in its own worktree, from the given branch or revision
+    ///
+    /// The patch of the `parent` session is applied to the worktree first, if any.
+    /// The Enigma server gets the first free port from the configured one, skipping `used_ports`
+    pub async fn new(host: &str, password: Option<String>, base: &str, details: SessionDetails, limits: SessionLimits,
+                     parent: Option<ParentPatch>, used_ports: &[u16]) -> Result<Session> {
         let settings = read_settings().await?;
-        let jar = PathBuf::from(repo::DIR).join(&settings.jar_file);
+        let id = Uuid::new_v4();
+        let worktree = PathBuf::from(repo::WORKTREES_DIR).join(id.to_string());
+        let port = find_free_port(settings.enigma_port, used_ports)?;
+
+        let rev = {
+            let repo = repo::open_worktree(repo::DIR)?;
+            let worktree_repo = repo::add_worktree(&repo, &id.to_string(), std::path::absolute(&worktree)?, base)?;
+            let rev = parent.as_ref().map_or(Ok(()), |parent| parent.apply_to(&worktree_repo))
+                .and_then(|_| Ok(repo::get_repo_head(&worktree_repo)?));
+            match rev {
+                Ok(rev) => rev,
+                Err(e) => {
+                    discard(id);
+                    return Err(e);
+                }
+            }
+        };
 
         let mut session = Session {
-            id: Uuid::new_v4(),
+            id,
             date: Utc::now(),
-            rev: repo::get_head()?,
-            jar_info: JarInfo::new(jar)?,
+            rev,
+            jar_info: JarInfo::default(),
+            base: base.to_string(),
+            details,
+            parent: parent.map(|p| p.id),
+            host: host.to_string(),
+            merge_commit: None,
+            port,
+            worktree,
             password,
+            state: SessionState::Starting,
+            transitions: vec![Transition {
+                state: SessionState::Starting,
+                date: Utc::now(),
+            }],
+            exit_info: None,
+            limits: Some(limits),
+            last_activity: None,
+            last_patch_hash: None,
+            process_info: None,
             pid: None,
+            child: None,
         };
 
-        session.launch(settings).await?;
-        session.write()?;
+        // Written before launching, so the session can be recovered if CoLab stops meanwhile
+        if let Err(e) = fs::create_dir_all(session.get_dir()).map_err(Into::into).and_then(|_| session.write()) {
+            discard(id);
+            return Err(e);
+        }
+        if let Err(e) = session.launch(settings).await {
+            discard(id);
+            return Err(e);
+        }
 
-        Ok(session)
+        // Errors aren't `Send`, so only the message is kept while killing the server
+        let msg = match session.transition(SessionState::Running) {
+            Ok(_) => return Ok(session),
+            Err(e) => e.to_string(),
+        };
+        session.kill_server().await;
+        discard(id);
+        throw!("{}", msg)
     }
 
     async fn launch(&mut self, settings: Settings) -> Result<()> {
-        let dir = self.get_dir();
-        fs::create_dir_all(&dir)?;
+        // The jar is usually ignored by git, so it's only in the main repository, where the pull command puts it
+        let jar = std::path::absolute(Path::new(repo::DIR).join(&settings.jar_file))?;
+        self.jar_info = JarInfo::new(&jar)?;
 
-        repo::run_command(&settings.pre_session_cmd)?;
+        let dir = self.get_dir();
+        repo::run_command(&settings.pre_session_cmd, &self.worktree)?;
 
-        let stdout = File::create(dir.join("stdout.log"))?;
-        let stderr = File::create(dir.join("stderr.log"))?;
-        let mut command = Command::new("java");
+        let stdout = File::create(dir.join(STDOUT_FILE))?;
+        let stderr = File::create(dir.join(STDERR_FILE))?;
+        let mut command = launcher::from_settings(&settings)?.command(&LaunchParams {
+            dir: &self.worktree,
+            jar: some_or_throw!(jar.to_str(), "Invalid jar file path"),
+            mappings: &settings.mappings_file,
+            port: self.port,
+            password: self.password.as_deref(),
+        });
 
         command
-            .current_dir("data/repo/")
+            .current_dir(&self.worktree)
+            // Its own process group, so it can be stopped along with any helper processes
+            .process_group(0)
             .stdout(stdout)
-            .stderr(stderr)
-            .arg("-cp")
-            .arg(settings.classpath) // TODO: Launch through gradle
-            .arg(settings.enigma_main_class)
-            .arg("-jar")
-            .arg(settings.jar_file)
-            .arg("-mappings")
-            .arg(settings.mappings_file);
+            .stderr(stderr);
 
-        if let Some(password) = &self.password {
-            command.arg("-password")
-                .arg(password);
-        }
+        let child = command.spawn()?;
+        self.pid = Some(child.id());
+        self.child = Some(child);
+        // Errors aren't `Send`, so only the message is kept while killing it
+        let msg = match self.track_server(&dir).await {
+            Ok(_) => return Ok(()),
+            Err(e) => e.to_string(),
+        };
+        self.kill_server().await;
+        throw!("{}", msg)
+    }
+
+    /// Record the pid and identity of the just started Enigma server, then wait for it to be ready
+    async fn track_server(&mut self, dir: &Path) -> Result<()> {
+        let pid = some_or_throw!(self.pid, "The Enigma server isn't running");
+        Session::write_pid(dir.join(PID_FILE), pid)?;
+        self.process_info = ProcessInfo::read(pid)?;
+        // So the server can be identified and stopped if CoLab stops while it's starting
+        self.write()?;
 
-        for arg in settings.enigma_args.split(" ") {
-            command.arg(arg);
+        self.wait_for_server().await
+    }
+
+    /// Kill the Enigma server of a session that failed to start, if it's still running
+    async fn kill_server(&mut self) {
+        if let Some(pid) = self.pid.take() {
+            if let Err(e) = process::terminate(pid, self.child.as_mut(), Duration::ZERO).await {
+                eprintln!("Failed to kill the Enigma server of session {}: {e}", self.id);
+            }
         }
+        self.child = None;
+    }
 
-        let pid = command.spawn()?.id();
-        Session::write_pid(dir.join(PID_FILE), pid)?;
-        self.pid = Some(pid);
+    /// Wait until the Enigma server listens on its port, failing with the reason if it exits first
+    ///
+    /// Stops waiting after [`SERVER_START_TIMEOUT`], assuming it's still loading
+    async fn wait_for_server(&mut self) -> Result<()> {
+        let deadline = Instant::now() + SERVER_START_TIMEOUT;
+
+        while let Some(pid) = self.pid {
+            if let process::State::Exited(status) = process::poll(pid, self.child.as_mut())? {
+                self.exited(status)?;
+                let log = fs::read_to_string(self.get_log_file(LogStream::Stderr)).unwrap_or_default();
+                if log.contains(ADDRESS_IN_USE) {
+                    throw!("Port {} was taken by another program before the Enigma server could use it", self.port)
+                }
+
+                // The last line that isn't part of a stack trace, usually the cause of the error
+                let reason = log.lines().rev()
+                    .find(|line| !line.trim().is_empty() && !line.starts_with(char::is_whitespace))
+                    .unwrap_or("no error output");
+                throw!("The Enigma server exited while starting: {}", reason)
+            }
+
+            if process::is_listening(self.port)? || Instant::now() >= deadline {
+                break;
+            }
+            sleep(SERVER_START_POLL_INTERVAL).await;
+        }
 
         Ok(())
     }
 
-    pub async fn finish(&mut self) -> Result<()> {
-        if !self.check_is_running()? {
-            return Ok(())
+    /// Mark the session as finishing, handing over its Enigma server to be stopped if it's running
+    async fn begin_finish(&mut self) -> Result<Option<ServerStop>> {
+        if self.state == SessionState::Finishing {
+            throw!("The session is already being finished")
+        }
+        if !self.worktree.exists() {
+            throw!("The working tree of the session doesn't exist anymore")
         }
 
-        let pid = self.pid.unwrap();
+        let settings = read_settings().await?;
+        self.transition(SessionState::Finishing)?;
 
-        Command::new("kill")
-            .arg(pid.to_string())
-            .status()?;
+        Ok(self.pid.take().map(|pid| ServerStop {
+            pid,
+            child: self.child.take(),
+            grace_period: Duration::from_secs(settings.shutdown_grace_period.into()),
+        }))
+    }
 
-        self.invalidate_pid()?;
-        self.write()?;
+    /// Take back the Enigma server after failing to stop it
+    fn abort_finish(&mut self, stop: ServerStop) -> Result<()> {
+        self.pid = Some(stop.pid);
+        self.child = stop.child;
+        self.transition(SessionState::Running)
+    }
 
-        let patch = repo::create_patch().await?;
-        repo::clear_working_tree().await?;
-        fs::write(self.get_file(PATCH_FILE), patch)?;
+    /// Record how the Enigma server exited if it was running, and whether saving the mappings diff worked
+    ///
+    /// The session is abandoned if it didn't, so finishing it can be retried
+    fn end_finish(&mut self, status: Option<Option<ExitStatus>>, saved: StdResult<(), String>) -> Result<()> {
+        let exited = status.map_or(Ok(()), |status| self.exited(status));
 
-        repo::run_command(&read_settings().await?.post_session_cmd)?;
+        match saved {
+            Ok(()) => self.transition(SessionState::Finished)?,
+            Err(e) => {
+                self.transition(SessionState::Abandoned)?;
+                throw!("{}", e)
+            }
+        }
 
-        Ok(())
+        exited
+    }
+}
+
+/// Save the mappings diff of a session being finished, then run the post session command and remove its worktree
+///
+/// Runs without holding the sessions, as the post session command may take a while
+async fn save_and_remove_worktree(id: Uuid, worktree: &Path, patch_file: &Path) -> Result<()> {
+    let settings = read_settings().await?;
+    let patch = repo::create_patch(worktree).await?;
+    // An earlier attempt may have failed after clearing the working tree, its diff is kept then
+    if !patch.is_empty() || !patch_file.exists() {
+        fs::write(patch_file, patch)?;
+    }
+    repo::clear_working_tree(worktree).await?;
+
+    let command = settings.post_session_cmd;
+    let dir = worktree.to_path_buf();
+    task::spawn_blocking(move || repo::run_command(&command, dir)).await??;
+
+    if worktree != Path::new(repo::DIR) {
+        let repo = repo::open_worktree(repo::DIR)?;
+        repo::remove_worktree(&repo, &id.to_string())?;
+    }
+
+    Ok(())
+}
+
+/// The Enigma server of a session being finished, taken out of the session so it can be stopped without holding it
+#[derive(Debug)]
+struct ServerStop {
+    pid: u32,
+    child: Option<Child>,
+    grace_period: Duration,
+}
+
+impl ServerStop {
+    async fn run(&mut self) -> IoResult<Option<ExitStatus>> {
+        process::terminate(self.pid, self.child.as_mut(), self.grace_period).await
     }
 }
 
@@ -213,6 +913,15 @@ impl JarInfo {
     }
 }
 
+impl From<ExitStatus> for ExitInfo {
+    fn from(status: ExitStatus) -> Self {
+        ExitInfo {
+            code: status.code(),
+            signal: status.signal(),
+        }
+    }
+}
+
 impl Default for JarInfo {
     fn default() -> Self {
         Self {
@@ -222,11 +931,183 @@ impl Default for JarInfo {
     }
 }
 
+/// Generate a random password for a new session
+pub fn generate_password() -> String {
+    util::random_string(PASSWORD_LENGTH)
+}
+
+/// Find the first port from `first` that isn't used by another session, nor by any other program
+///
+/// Nothing holds on to the port until the Enigma server binds it, if something else takes it in the meantime
+/// starting the session fails in [`Session::wait_for_server`]
+fn find_free_port(first: u16, used_ports: &[u16]) -> Result<u16> {
+    let port = (first..=first.saturating_add(MAX_PORT_ATTEMPTS))
+        .filter(|port| !used_ports.contains(port))
+        .find(|port| is_port_free(*port));
+
+    Ok(some_or_throw!(port, "No free port found for the Enigma server"))
+}
+
+/// Whether the port can be bound on all IPv4 and IPv6 addresses
+fn is_port_free(port: u16) -> bool {
+    TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).is_ok()
+        && match TcpListener::bind((Ipv6Addr::UNSPECIFIED, port)) {
+            Ok(_) => true,
+            // IPv6 may be disabled
+            Err(e) => e.kind() != ErrorKind::AddrInUse,
+        }
+}
+
+/// Check the activity of the Enigma server on `port`, serving the mappings of `worktree`, without touching its index
+pub async fn check_activity(port: u16, worktree: &Path) -> Result<Activity> {
+    Ok(Activity {
+        connected: process::count_connections(port)? > 0,
+        patch_hash: util::sha3_256(repo::current_patch(worktree).await?),
+    })
+}
+
+/// Stop the Enigma server of a session and save the mappings diff, also used to collect the diff of crashed and
+/// abandoned sessions
+///
+/// The sessions are only locked to update the session, not while stopping the server, which may take the whole grace
+/// period, nor while saving the diff and running the post session command
+pub async fn finish(sessions: &Mutex<Vec<Session>>, id: Uuid) -> Result<()> {
+    let (stop, worktree, patch_file) = {
+        let mut sessions = sessions.lock().await;
+        let session = some_or_throw!(sessions.iter_mut().find(|s| s.id == id), "Session not found");
+        session.check_process()?;
+        if session.state == SessionState::Finished {
+            return Ok(());
+        }
+
+        (session.begin_finish().await?, session.worktree.clone(), session.get_file(PATCH_FILE))
+    };
+
+    let status = match stop {
+        Some(mut stop) => match stop.run().await {
+            Ok(status) => Some(status),
+            Err(e) => {
+                if let Some(session) = sessions.lock().await.iter_mut().find(|s| s.id == id) {
+                    session.abort_finish(stop)?;
+                }
+                return Err(e)?;
+            }
+        },
+        None => None,
+    };
+
+    // Errors aren't `Send`, so only the message is kept while locking the sessions again
+    let saved = save_and_remove_worktree(id, &worktree, &patch_file).await.map_err(|e| e.to_string());
+
+    let mut sessions = sessions.lock().await;
+    let session = some_or_throw!(sessions.iter_mut().find(|s| s.id == id), "Session not found");
+    session.end_finish(status, saved)
+}
+
+/// Save the current mappings diff of a working tree to the snapshots in `dir`, without touching its index
+///
+/// Returns whether a snapshot was taken, see [`save_snapshot`]
+pub async fn snapshot(worktree: &Path, dir: &Path) -> Result<bool> {
+    let patch = repo::current_patch(worktree).await?;
+    Ok(save_snapshot(dir, &patch, Utc::now())?)
+}
+
+/// Save a mappings diff to the snapshots in `dir`, unless it's empty or the same as the previous snapshot
+///
+/// Returns whether a snapshot was taken
+fn save_snapshot(dir: &Path, patch: &[u8], date: DateTime<Utc>) -> IoResult<bool> {
+    if patch.is_empty() {
+        return Ok(false);
+    }
+
+    if let Some(last) = list_snapshots(dir)?.last() {
+        if fs::read(dir.join(&last.name))? == patch {
+            return Ok(false);
+        }
+    }
+
+    fs::create_dir_all(dir)?;
+    let name = format!("{}.{SNAPSHOT_EXTENSION}", date.format(SNAPSHOT_DATE_FORMAT));
+    fs::write(dir.join(name), patch)?;
+
+    Ok(true)
+}
+
+/// The snapshots in `dir`, oldest first
+fn list_snapshots(dir: &Path) -> IoResult<Vec<Snapshot>> {
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut snapshots = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.extension().is_none_or(|e| e != SNAPSHOT_EXTENSION) {
+            continue;
+        }
+
+        let date = path.file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| NaiveDateTime::parse_from_str(s, SNAPSHOT_DATE_FORMAT).ok());
+        if let (Some(name), Some(date)) = (entry.file_name().to_str(), date) {
+            snapshots.push(Snapshot {
+                name: name.to_string(),
+                date: date.and_utc(),
+                size: entry.metadata()?.len(),
+            });
+        }
+    }
+    snapshots.sort_by_key(|s| s.date);
+
+    Ok(snapshots)
+}
+
+/// The names of the users who joined, in the log of an Enigma server
+fn parse_logins(log: &str) -> Vec<String> {
+    let mut names: Vec<String> = vec![];
+    for line in log.lines() {
+        let name = line.split_once(LOG_PREFIX)
+            .and_then(|(_, message)| message.split_once(LOGIN_MARKER))
+            .map(|(name, _)| name.trim());
+        if let Some(name) = name.filter(|n| !n.is_empty()) {
+            if !names.iter().any(|n| n == name) {
+                names.push(name.to_string());
+            }
+        }
+    }
+
+    names
+}
+
+/// Remove the worktree and the directory of a session that failed to start
+fn discard(id: Uuid) {
+    if let Err(e) = repo::open_worktree(repo::DIR).and_then(|r| repo::remove_worktree(&r, &id.to_string())) {
+        eprintln!("Failed to remove the worktree of session {id}: {e}");
+    }
+
+    let dir = PathBuf::from(DIR).join(id.to_string());
+    if dir.exists() {
+        if let Err(e) = fs::remove_dir_all(dir) {
+            eprintln!("Failed to remove the directory of session {id}: {e}");
+        }
+    }
+}
+
+fn default_worktree() -> PathBuf {
+    PathBuf::from(repo::DIR)
+}
+
+fn default_port() -> u16 {
+    DEFAULT_ENIGMA_PORT
+}
+
 fn default_rev() -> String {
     "unknown HEAD revision".to_string()
 }
 
-pub fn load_sessions() -> Result<Vec<Session>> {
+/// Load the sessions, giving `default_limits` to the ones without limits
+pub async fn load_sessions(default_limits: SessionLimits) -> Result<Vec<Session>> {
     let mut sessions = vec![];
     let dir = Path::new(DIR);
 
@@ -238,8 +1119,23 @@ pub fn load_sessions() -> Result<Vec<Session>> {
             let file_type = entry.file_type()?;
 
             if file_type.is_dir() {
+                // Left behind by older versions when a session failed to start, or by a start that couldn't be cleaned up
+                if !entry.path().join(SESSION_FILE).exists() {
+                    eprintln!("Skipping {}, it has no {SESSION_FILE}", entry.path().display());
+                    continue;
+                }
+
                 let mut session = Session::read(entry.path())?;
+                if session.limits.is_none() {
+                    session.limits = Some(default_limits);
+                    session.write()?;
+                }
+                let interrupted_start = session.state == SessionState::Starting;
+                session.recover_state()?;
                 session.check_process()?;
+                if interrupted_start {
+                    session.clean_up_start().await;
+                }
                 sessions.push(session);
             }
         }
@@ -252,3 +1148,75 @@ pub fn serialize_running<S>(value: &Option<u32>, serializer: S) -> StdResult<S::
     where S: Serializer {
     serializer.serialize_bool(value.is_some())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_details() {
+        let details = SessionDetails::new("  Worldgen  ", "\nMap the `worldgen` package\n", "worldgen, biomes,,Worldgen , ");
+        assert_eq!("Worldgen", details.title);
+        assert_eq!("Map the `worldgen` package", details.description);
+        assert_eq!(vec!["worldgen", "biomes"], details.tags);
+
+        assert!(SessionDetails::new("", "", " , ").tags.is_empty());
+    }
+
+    #[test]
+    fn test_parse_logins() {
+        let log = "[server] Server started on port 34712
+[server] alice logged in with IP 127.0.0.1:51234
+[server] bob logged in with IP 127.0.0.1:51236
+[server] alice left the server
+[server] alice logged in with IP 127.0.0.1:51240
+Not a login: carol logged in
+";
+
+        assert_eq!(vec!["alice", "bob"], parse_logins(log));
+    }
+
+    #[test]
+    fn test_interrupted_start() -> Result<()> {
+        // As written by `Session::new` before launching the Enigma server
+        let session: Session = toml::from_str(r#"
+            id = "6f1c2a3e-8d6b-4f0e-9c1a-2b3d4e5f6a7b"
+            date = "2026-10-01T10:00:00Z"
+            base = "master"
+            state = "starting"
+
+            [[transitions]]
+            state = "starting"
+            date = "2026-10-01T10:00:00Z"
+        "#)?;
+        assert_eq!(Some(SessionState::Abandoned), session.interrupted_state());
+
+        let running = Session { state: SessionState::Running, ..session };
+        assert_eq!(None, running.interrupted_state());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_snapshot() -> IoResult<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let dir = temp_dir.path().join(SNAPSHOTS_DIR);
+        let date = DateTime::parse_from_rfc3339("2026-10-01T10:00:00Z").expect("Invalid date").to_utc();
+
+        assert!(!save_snapshot(&dir, b"", date)?, "Saved an empty snapshot");
+        assert!(list_snapshots(&dir)?.is_empty());
+
+        assert!(save_snapshot(&dir, b"first", date)?);
+        assert!(!save_snapshot(&dir, b"first", date + ChronoDuration::minutes(1))?, "Saved an unchanged snapshot");
+        assert!(save_snapshot(&dir, b"second", date + ChronoDuration::minutes(2))?);
+        // Only compared with the previous one
+        assert!(save_snapshot(&dir, b"first", date + ChronoDuration::minutes(3))?);
+
+        let snapshots = list_snapshots(&dir)?;
+        assert_eq!(vec!["20261001T100000Z.patch", "20261001T100200Z.patch", "20261001T100300Z.patch"],
+                   snapshots.iter().map(|s| s.name.as_str()).collect::<Vec<_>>());
+        assert_eq!(b"second".to_vec(), fs::read(dir.join(&snapshots[1].name))?);
+
+        temp_dir.close()
+    }
+}
@@ -3,26 +3,55 @@ use std::fs;
 use std::path::Path;
 
 use rocket::serde::{Deserialize, Serialize};
+use toml::{Table, Value};
 
-#[derive(Debug, Deserialize, Serialize)]
+use crate::launcher::LauncherKind;
+
+/// Default port of the Enigma server
+pub const DEFAULT_ENIGMA_PORT: u16 = 34712;
+/// Settings whose values are kept out of the audit log
+const SECRET_KEYS: [&str; 2] = ["token", "ssh_passphrase"];
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
 pub struct Settings {
     pub repo: RepoSettings,
+    pub remote: RemoteSettings,
+    /// Relative to the main repository, where the pull command builds or downloads it
     pub jar_file: String,
     pub mappings_file: String,
-    pub auto_save_interval: u16, // TODO
+    /// Seconds between snapshots of the running sessions
+    pub auto_save_interval: u16,
     pub pull_cmd: String,
     pub pre_session_cmd: String,
     pub post_session_cmd: String,
     pub enigma_args: String,
+    pub launcher: LauncherKind,
     pub enigma_main_class: String,
     pub classpath: String,
-    // TODO: Save last password
+    /// Task run by the Gradle launcher
+    pub gradle_task: String,
+    /// Shell command run by the custom command launcher
+    pub launch_command: String,
+    /// Host name shown to users joining a session, the one of the web page is used if empty
+    pub public_host: String,
+    pub enigma_port: u16,
+    /// Seconds given to the Enigma server to save and exit when finishing a session, before it's killed
+    pub shutdown_grace_period: u16,
+    /// Default hours after which sessions are finished, 0 for no limit
+    pub max_session_hours: u16,
+    /// Default minutes without connected users nor changes to the mappings after which sessions are finished, 0 for no limit
+    pub session_idle_minutes: u16,
+    /// Message of the commits of merged sessions, with `{title}`, `{id}`, `{date}`, `{base}`, `{rev}`
+    /// and `{collaborators}` replaced by those of the session
+    pub merge_message_template: String,
 }
 
 impl Default for Settings {
     fn default() -> Self {
         Settings {
             repo: RepoSettings::default(),
+            remote: RemoteSettings::default(),
             jar_file: "file.jar".to_string(),
             mappings_file: "mappings/".to_string(),
             auto_save_interval: 120,
@@ -30,13 +59,22 @@ impl Default for Settings {
             pre_session_cmd: "".to_string(),
             post_session_cmd: "".to_string(),
             enigma_args: "".to_string(),
+            launcher: LauncherKind::default(),
             enigma_main_class: "org.quiltmc.enigma.network.DedicatedEnigmaServer".to_string(),
             classpath: "".to_string(),
+            gradle_task: "enigma".to_string(),
+            launch_command: "".to_string(),
+            public_host: "".to_string(),
+            enigma_port: DEFAULT_ENIGMA_PORT,
+            shutdown_grace_period: 30,
+            max_session_hours: 24,
+            session_idle_minutes: 60,
+            merge_message_template: "{title}\n\nMappings from session {id}, started on {date} at {base} ({rev})\n\nCollaborators: {collaborators}\n".to_string(),
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, FromForm)]
+#[derive(Debug, Clone, Serialize, Deserialize, FromForm)]
 pub struct RepoSettings {
     pub url: String,
     pub branch: String,
@@ -51,6 +89,57 @@ impl Default for RepoSettings {
     }
 }
 
+/// Where to push the merged sessions, and how to authenticate to the remotes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RemoteSettings {
+    /// Remote the checked out branch is pushed to
+    pub push_remote: String,
+    /// User name for HTTPS and SSH, the one in the remote URL if empty
+    pub username: String,
+    /// Personal access token, sent as the HTTPS password
+    pub token: String,
+    /// Private key file for SSH
+    pub ssh_key: String,
+    pub ssh_passphrase: String,
+}
+
+impl Default for RemoteSettings {
+    fn default() -> Self {
+        RemoteSettings {
+            push_remote: "origin".to_string(),
+            username: "".to_string(),
+            token: "".to_string(),
+            ssh_key: "".to_string(),
+            ssh_passphrase: "".to_string(),
+        }
+    }
+}
+
+impl Settings {
+    /// Describe the settings that differ from `old`, i.e. for the audit log
+    pub fn describe_changes(&self, old: &Settings) -> String {
+        let mut changes = vec![];
+        if let (Ok(Value::Table(new)), Ok(Value::Table(old))) = (Value::try_from(self), Value::try_from(old)) {
+            collect_changes("", &new, &old, &mut changes);
+        }
+
+        changes.join(", ")
+    }
+}
+
+fn collect_changes(prefix: &str, new: &Table, old: &Table, changes: &mut Vec<String>) {
+    for (key, value) in new {
+        match (value, old.get(key)) {
+            (Value::Table(new), Some(Value::Table(old))) => collect_changes(&format!("{prefix}{key}."), new, old, changes),
+            (value, Some(old)) if value == old => {},
+            _ if SECRET_KEYS.contains(&key.as_str()) => changes.push(format!("{prefix}{key} changed")),
+            (value, Some(old)) => changes.push(format!("{prefix}{key}: {old} -> {value}")),
+            (value, None) => changes.push(format!("{prefix}{key}: {value}")),
+        }
+    }
+}
+
 pub async fn read_settings() -> Result<Settings, Box<dyn Error>> {
     let path = Path::new("data/CoLab.toml");
     if path.exists() {
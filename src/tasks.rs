@@ -0,0 +1,137 @@
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use rocket::{Orbit, Rocket};
+use rocket::fairing::AdHoc;
+use rocket::tokio;
+use rocket::tokio::time::sleep;
+
+use crate::{sessions, SessionList};
+use crate::settings::read_settings;
+
+/// Shortest interval between auto saves, to avoid hammering the repository with a misconfigured interval
+const MIN_AUTO_SAVE_INTERVAL: u16 = 15;
+/// Seconds between checks of the activity and limits of the running sessions
+const LIMITS_CHECK_INTERVAL: u64 = 60;
+
+/// Periodically snapshot the mappings of the running sessions, every [`Settings::auto_save_interval`] seconds
+///
+/// [`Settings::auto_save_interval`]: crate::settings::Settings::auto_save_interval
+pub fn auto_save() -> AdHoc {
+    AdHoc::on_liftoff("Auto save", |rocket| Box::pin(async move {
+        spawn_auto_save(rocket);
+    }))
+}
+
+fn spawn_auto_save(rocket: &Rocket<Orbit>) {
+    let Some(sessions) = rocket.state::<SessionList>().cloned() else {
+        eprintln!("Sessions not loaded, auto saving is disabled");
+        return;
+    };
+    let mut shutdown = rocket.shutdown();
+
+    tokio::spawn(async move {
+        let mut last_save = Instant::now();
+
+        loop {
+            // Wake up regularly, so changes to the interval are picked up quickly
+            tokio::select! {
+                _ = sleep(Duration::from_secs(MIN_AUTO_SAVE_INTERVAL.into())) => {},
+                _ = &mut shutdown => break,
+            }
+
+            let interval = match read_settings().await {
+                Ok(settings) => settings.auto_save_interval.max(MIN_AUTO_SAVE_INTERVAL),
+                Err(e) => {
+                    eprintln!("Failed to read settings for auto saving: {e}");
+                    continue;
+                }
+            };
+            if last_save.elapsed() < Duration::from_secs(interval.into()) {
+                continue;
+            }
+            last_save = Instant::now();
+
+            // Diffing can take a while, so the sessions are only held to find the running ones
+            let running: Vec<_> = sessions.lock().await.iter_mut()
+                .filter_map(|session| match session.check_is_running() {
+                    Ok(running) => running.then(|| (session.id, session.snapshot_paths())),
+                    Err(e) => {
+                        eprintln!("Failed to check if session {} is running: {e}", session.id);
+                        None
+                    }
+                })
+                .collect();
+
+            for (id, (worktree, dir)) in running {
+                if let Err(e) = sessions::snapshot(&worktree, &dir).await {
+                    eprintln!("Failed to auto save session {id}: {e}");
+                }
+            }
+        }
+    });
+}
+
+/// Finish the running sessions that went over their duration or idle limit
+pub fn session_limits() -> AdHoc {
+    AdHoc::on_liftoff("Session limits", |rocket| Box::pin(async move {
+        spawn_session_limits(rocket);
+    }))
+}
+
+fn spawn_session_limits(rocket: &Rocket<Orbit>) {
+    let Some(sessions) = rocket.state::<SessionList>().cloned() else {
+        eprintln!("Sessions not loaded, session limits are disabled");
+        return;
+    };
+    let mut shutdown = rocket.shutdown();
+
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = sleep(Duration::from_secs(LIMITS_CHECK_INTERVAL)) => {},
+                _ = &mut shutdown => break,
+            }
+
+            // Checking the activity diffs the mappings, so the sessions are only held to find and update them
+            let running: Vec<_> = sessions.lock().await.iter_mut()
+                .filter_map(|session| match session.check_is_running() {
+                    Ok(running) => running.then(|| (session.id, session.port, session.worktree().to_path_buf())),
+                    Err(e) => {
+                        eprintln!("Failed to check if session {} is running: {e}", session.id);
+                        None
+                    }
+                })
+                .collect();
+
+            let mut expired = vec![];
+            for (id, port, worktree) in running {
+                let activity = sessions::check_activity(port, &worktree).await
+                    .map_err(|e| eprintln!("Failed to check the activity of session {id}: {e}"))
+                    .ok();
+
+                let mut sessions = sessions.lock().await;
+                let Some(session) = sessions.iter_mut().find(|s| s.id == id) else {
+                    continue;
+                };
+                if let Some(activity) = activity {
+                    if let Err(e) = session.record_activity(activity) {
+                        eprintln!("Failed to record the activity of session {id}: {e}");
+                    }
+                }
+
+                if let Some(deadline) = session.deadline().filter(|d| d.date <= Utc::now()) {
+                    println!("Finishing session {id}, {:?} limit reached", deadline.reason);
+                    expired.push(id);
+                }
+            }
+
+            // Finishing locks the sessions itself
+            for id in expired {
+                if let Err(e) = sessions::finish(&sessions, id).await {
+                    eprintln!("Failed to finish session {id}: {e}");
+                }
+            }
+        }
+    });
+}
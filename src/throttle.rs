@@ -0,0 +1,294 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+/// Failed attempts allowed before the backoff starts
+const FREE_ATTEMPTS: u32 = 3;
+/// Failed attempts after which logins are locked out for [`LOCKOUT_DURATION`]
+const LOCKOUT_ATTEMPTS: u32 = 10;
+const BASE_DELAY_SECONDS: i64 = 2;
+const LOCKOUT_DURATION_MINUTES: i64 = 15;
+/// Failures are forgotten after this long without another failed attempt
+const RESET_AFTER_HOURS: i64 = 12;
+const MAX_RECORDS: usize = 200;
+/// Addresses and accounts tracked at once, the ones without recent failures are forgotten first
+const MAX_TRACKED: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Ip(IpAddr),
+    Account(String),
+}
+
+#[derive(Debug)]
+struct Attempts {
+    failures: u32,
+    last_failure: DateTime<Utc>,
+    blocked_until: DateTime<Utc>,
+}
+
+/// A login attempt rejected because of too many failures
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockedAttempt {
+    pub date: DateTime<Utc>,
+    pub ip: Option<IpAddr>,
+    pub user: String,
+    pub blocked_until: DateTime<Utc>,
+}
+
+/// Tracks failed logins per IP address and per account, delaying further attempts exponentially
+#[derive(Debug, Default)]
+pub struct LoginThrottle {
+    attempts: HashMap<Key, Attempts>,
+    blocked: VecDeque<BlockedAttempt>,
+}
+
+impl Attempts {
+    fn is_stale(&self, now: DateTime<Utc>) -> bool {
+        now - self.last_failure > Duration::hours(RESET_AFTER_HOURS)
+    }
+}
+
+fn keys(ip: Option<IpAddr>, user: &str) -> Vec<Key> {
+    let mut keys = vec![Key::Account(user.to_string())];
+    if let Some(ip) = ip {
+        keys.push(Key::Ip(ip));
+    }
+
+    keys
+}
+
+/// How long logins are blocked after the given amount of consecutive failures
+fn block_duration(failures: u32) -> Duration {
+    if failures >= LOCKOUT_ATTEMPTS {
+        Duration::minutes(LOCKOUT_DURATION_MINUTES)
+    } else if failures > FREE_ATTEMPTS {
+        Duration::seconds(BASE_DELAY_SECONDS << (failures - FREE_ATTEMPTS - 1))
+    } else {
+        Duration::zero()
+    }
+}
+
+impl LoginThrottle {
+    /// Check if a login attempt is allowed, recording it if it isn't
+    ///
+    /// Returns the time until which the attempt is blocked
+    pub fn check(&mut self, ip: Option<IpAddr>, user: &str, now: DateTime<Utc>) -> Result<(), DateTime<Utc>> {
+        let blocked_until = keys(ip, user).iter()
+            .filter_map(|k| self.attempts.get(k))
+            .filter(|a| !a.is_stale(now))
+            .map(|a| a.blocked_until)
+            .filter(|until| *until > now)
+            .max();
+
+        match blocked_until {
+            Some(until) => {
+                if self.blocked.len() >= MAX_RECORDS {
+                    self.blocked.pop_front();
+                }
+                self.blocked.push_back(BlockedAttempt {
+                    date: now,
+                    ip,
+                    user: user.to_string(),
+                    blocked_until: until,
+                });
+                println!("Blocked a login attempt for '{user}' from {ip:?} until {until}");
+
+                Err(until)
+            }
+            None => Ok(())
+        }
+    }
+
+    /// Check if a login attempt is allowed, counting it as failed until [`Self::record_success`] is called
+    ///
+    /// Attempts that are verified without holding the throttle must use this instead of [`Self::check`],
+    /// so concurrent attempts can't all pass the check before any of them records its failure
+    pub fn reserve(&mut self, ip: Option<IpAddr>, user: &str, now: DateTime<Utc>) -> Result<(), DateTime<Utc>> {
+        self.check(ip, user, now)?;
+        self.record_failure(ip, user, now);
+        Ok(())
+    }
+
+    pub fn record_failure(&mut self, ip: Option<IpAddr>, user: &str, now: DateTime<Utc>) {
+        for key in keys(ip, user) {
+            if !self.attempts.contains_key(&key) {
+                self.make_room(now);
+            }
+
+            let attempts = self.attempts.entry(key).or_insert(Attempts {
+                failures: 0,
+                last_failure: now,
+                blocked_until: now,
+            });
+            if attempts.is_stale(now) {
+                attempts.failures = 0;
+            }
+
+            attempts.failures += 1;
+            attempts.last_failure = now;
+            attempts.blocked_until = now + block_duration(attempts.failures);
+        }
+    }
+
+    /// Forget stale failures if the map is full, and the oldest ones if that's not enough
+    fn make_room(&mut self, now: DateTime<Utc>) {
+        if self.attempts.len() < MAX_TRACKED {
+            return;
+        }
+
+        self.attempts.retain(|_, a| !a.is_stale(now));
+        if self.attempts.len() >= MAX_TRACKED {
+            let oldest = self.attempts.iter()
+                .min_by_key(|(_, a)| a.last_failure)
+                .map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest {
+                self.attempts.remove(&oldest);
+            }
+        }
+    }
+
+    /// Forget the failures of the account, and take back the failure counted by [`Self::reserve`] for the address
+    ///
+    /// The other failures of the address are kept, so logging into another account doesn't reset its backoff
+    pub fn record_success(&mut self, ip: Option<IpAddr>, user: &str) {
+        self.attempts.remove(&Key::Account(user.to_string()));
+
+        let Some(ip) = ip else {
+            return;
+        };
+        if let Some(attempts) = self.attempts.get_mut(&Key::Ip(ip)) {
+            attempts.failures = attempts.failures.saturating_sub(1);
+            if attempts.failures == 0 {
+                self.attempts.remove(&Key::Ip(ip));
+            } else {
+                attempts.blocked_until = attempts.last_failure + block_duration(attempts.failures);
+            }
+        }
+    }
+
+    /// Recently blocked attempts, newest first
+    pub fn blocked_attempts(&self) -> impl Iterator<Item = &BlockedAttempt> {
+        self.blocked.iter().rev()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const IP: Option<IpAddr> = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    const OTHER_IP: Option<IpAddr> = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+
+    #[test]
+    fn test_backoff() {
+        let mut throttle = LoginThrottle::default();
+        let now = Utc::now();
+
+        for _ in 0..FREE_ATTEMPTS {
+            assert!(throttle.check(IP, "user", now).is_ok());
+            throttle.record_failure(IP, "user", now);
+        }
+        assert!(throttle.check(IP, "user", now).is_ok(), "Blocked before the free attempts ran out");
+
+        throttle.record_failure(IP, "user", now);
+        assert_eq!(Err(now + Duration::seconds(BASE_DELAY_SECONDS)), throttle.check(IP, "user", now));
+        assert!(throttle.check(IP, "user", now + Duration::seconds(BASE_DELAY_SECONDS)).is_ok());
+
+        throttle.record_failure(IP, "user", now);
+        assert_eq!(Err(now + Duration::seconds(BASE_DELAY_SECONDS * 2)), throttle.check(IP, "user", now));
+
+        assert_eq!(2, throttle.blocked_attempts().count());
+    }
+
+    #[test]
+    fn test_reserve() {
+        let mut throttle = LoginThrottle::default();
+        let now = Utc::now();
+
+        for _ in 0..=FREE_ATTEMPTS {
+            assert!(throttle.reserve(IP, "user", now).is_ok());
+        }
+        // The attempts above are still being verified, but count as failures already
+        assert_eq!(Err(now + Duration::seconds(BASE_DELAY_SECONDS)), throttle.reserve(IP, "user", now));
+
+        throttle.record_success(IP, "user");
+        assert!(throttle.reserve(IP, "user", now).is_ok());
+    }
+
+    #[test]
+    fn test_lockout() {
+        let mut throttle = LoginThrottle::default();
+        let now = Utc::now();
+
+        for _ in 0..LOCKOUT_ATTEMPTS {
+            throttle.record_failure(IP, "user", now);
+        }
+
+        let lockout = now + Duration::minutes(LOCKOUT_DURATION_MINUTES);
+        // Both the account and the address are locked out
+        assert_eq!(Err(lockout), throttle.check(OTHER_IP, "user", now));
+        assert_eq!(Err(lockout), throttle.check(IP, "other", now));
+        assert!(throttle.check(OTHER_IP, "other", now).is_ok());
+        assert!(throttle.check(IP, "user", lockout).is_ok());
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut throttle = LoginThrottle::default();
+        let now = Utc::now();
+
+        for _ in 0..LOCKOUT_ATTEMPTS {
+            throttle.record_failure(IP, "user", now);
+        }
+        throttle.record_success(IP, "user");
+        assert!(throttle.check(OTHER_IP, "user", now).is_ok());
+
+        for _ in 0..LOCKOUT_ATTEMPTS - 1 {
+            throttle.record_failure(IP, "user", now);
+        }
+        let later = now + Duration::hours(RESET_AFTER_HOURS + 1);
+        throttle.record_failure(IP, "user", later);
+        assert!(throttle.check(IP, "user", later).is_ok(), "Old failures weren't forgotten");
+    }
+
+    #[test]
+    fn test_success_keeps_address() {
+        let mut throttle = LoginThrottle::default();
+        let now = Utc::now();
+
+        for i in 0..FREE_ATTEMPTS {
+            assert!(throttle.reserve(IP, &format!("victim{i}"), now).is_ok());
+        }
+        // Logging into an account of their own between guesses doesn't reset the backoff of the address
+        assert!(throttle.reserve(IP, "attacker", now).is_ok());
+        throttle.record_success(IP, "attacker");
+
+        assert!(throttle.reserve(IP, "victim", now).is_ok());
+        assert_eq!(Err(now + Duration::seconds(BASE_DELAY_SECONDS)), throttle.reserve(IP, "other victim", now));
+    }
+
+    #[test]
+    fn test_max_tracked() {
+        let mut throttle = LoginThrottle::default();
+        let now = Utc::now();
+
+        for i in 0..MAX_TRACKED {
+            throttle.record_failure(None, &format!("user{i}"), now + Duration::seconds(i as i64));
+        }
+        assert_eq!(MAX_TRACKED, throttle.attempts.len());
+
+        let later = now + Duration::seconds(MAX_TRACKED as i64);
+        throttle.record_failure(None, "other", later);
+        assert_eq!(MAX_TRACKED, throttle.attempts.len());
+        assert!(!throttle.attempts.contains_key(&Key::Account("user0".to_string())), "The oldest failures weren't forgotten");
+
+        let stale = later + Duration::hours(RESET_AFTER_HOURS + 1);
+        throttle.record_failure(None, "another", stale);
+        assert_eq!(1, throttle.attempts.len(), "Stale failures weren't forgotten");
+    }
+}
@@ -0,0 +1,121 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use qrcode::QrCode;
+use qrcode::render::svg;
+use rand::RngCore;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::util;
+
+const ISSUER: &str = "Enigma CoLab";
+const SECRET_BYTES: usize = 20;
+const DIGITS: usize = 6;
+const STEP: u64 = 30;
+/// Codes from the previous and next steps are accepted too, to allow for clock drift
+const SKEW: u64 = 1;
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 10;
+
+/// Generate a new base32-encoded secret
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; SECRET_BYTES];
+    rand::thread_rng().fill_bytes(&mut bytes);
+
+    match Secret::Raw(bytes.to_vec()).to_encoded() {
+        Secret::Encoded(s) => s,
+        Secret::Raw(_) => unreachable!(),
+    }
+}
+
+fn totp(secret: &str, account_name: &str) -> Option<TOTP> {
+    let bytes = Secret::Encoded(secret.to_string()).to_bytes().ok()?;
+    Some(TOTP::new_unchecked(Algorithm::SHA1, DIGITS, SKEW as u8, STEP, bytes,
+                             Some(ISSUER.to_string()), account_name.to_string()))
+}
+
+/// The `otpauth://` URI used by authenticator apps
+pub fn otpauth_uri(secret: &str, account_name: &str) -> Option<String> {
+    totp(secret, account_name).map(|t| t.get_url())
+}
+
+/// The `otpauth://` URI as a QR code, in SVG format
+pub fn qr_code_svg(uri: &str) -> Option<String> {
+    let code = QrCode::new(uri.as_bytes()).ok()?;
+    Some(code.render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .build())
+}
+
+/// Check a code, returning the time step it belongs to
+///
+/// Codes from steps up to `last_step` are rejected, so each code can only be used once
+pub fn verify(secret: &str, code: &str, last_step: u64) -> Option<u64> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    verify_at(secret, code, last_step, now)
+}
+
+fn verify_at(secret: &str, code: &str, last_step: u64, time: u64) -> Option<u64> {
+    let totp = totp(secret, "")?;
+    let code = code.trim();
+    let current = time / STEP;
+
+    (current.saturating_sub(SKEW)..=current + SKEW)
+        .filter(|step| *step > last_step)
+        .find(|step| util::constant_time_eq(&totp.generate(step * STEP), code))
+}
+
+/// Generate a new set of single-use recovery codes
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code = util::random_string(RECOVERY_CODE_LENGTH).to_lowercase();
+            let (a, b) = code.split_at(RECOVERY_CODE_LENGTH / 2);
+            format!("{a}-{b}")
+        })
+        .collect()
+}
+
+/// Normalize a recovery code as typed by the user, before hashing it
+pub fn normalize_recovery_code(code: &str) -> String {
+    code.trim().to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let secret = generate_secret();
+        let totp = totp(&secret, "user").expect("Invalid secret");
+        let time = 1_700_000_000;
+        let step = time / STEP;
+
+        let code = totp.generate(time);
+        assert_eq!(Some(step), verify_at(&secret, &code, 0, time));
+        assert_eq!(Some(step), verify_at(&secret, &code, 0, time + STEP), "Clock drift not accepted");
+        assert_eq!(None, verify_at(&secret, &code, 0, time + STEP * 3), "Old code accepted");
+        assert_eq!(None, verify_at(&secret, &code, step, time), "Code accepted twice");
+        assert_eq!(None, verify_at(&secret, "000000x", 0, time));
+        assert_eq!(None, verify_at("not base32!", &code, 0, time));
+    }
+
+    #[test]
+    fn test_otpauth_uri() {
+        let secret = generate_secret();
+        let uri = otpauth_uri(&secret, "user").expect("Invalid secret");
+
+        assert!(uri.starts_with("otpauth://totp/"));
+        assert!(uri.contains(&secret));
+        assert!(qr_code_svg(&uri).is_some_and(|svg| svg.contains("<svg")));
+    }
+
+    #[test]
+    fn test_recovery_codes() {
+        let codes = generate_recovery_codes();
+
+        assert_eq!(RECOVERY_CODE_COUNT, codes.len());
+        assert!(codes.iter().all(|c| c.len() == RECOVERY_CODE_LENGTH + 1 && c == &normalize_recovery_code(c)));
+        assert_eq!("abcde-12345", normalize_recovery_code(" ABCDE-12345 "));
+    }
+}
@@ -0,0 +1,471 @@
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::result::Result as StdResult;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{totp, util};
+use crate::util::{some_or_throw, throw};
+
+const FILE: &str = "data/users.toml";
+const INVITE_TOKEN_LENGTH: usize = 32;
+const API_TOKEN_LENGTH: usize = 40;
+const API_TOKEN_PREFIX: &str = "colab_";
+
+type Result<T> = StdResult<T, Box<dyn Error>>;
+
+/// User roles, each one including the permissions of the previous ones
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, FromFormField)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Can see the sessions
+    Viewer,
+    /// Can join the sessions
+    #[default]
+    Mapper,
+    /// Can start and finish sessions
+    #[field(value = "session_host")]
+    SessionHost,
+    /// Can manage the repository, settings and users
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Mapper => "mapper",
+            Role::SessionHost => "session_host",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Account {
+    pub id: Uuid,
+    pub name: String,
+    password_hash: String,
+    #[serde(default)]
+    pub role: Role,
+    // Replaced by `role`, only read to migrate older user stores
+    #[serde(default, skip_serializing)]
+    admin: bool,
+    #[serde(default)]
+    pub disabled: bool,
+    pub created: DateTime<Utc>,
+    #[serde(default)]
+    tokens: Vec<ApiToken>,
+    #[serde(default)]
+    two_factor: Option<TwoFactor>,
+}
+
+/// Time-based one-time password (TOTP) settings of an account
+#[derive(Debug, Serialize, Deserialize)]
+struct TwoFactor {
+    /// Base32-encoded TOTP secret
+    secret: String,
+    /// Whether the setup was confirmed with a valid code
+    enabled: bool,
+    /// Time step of the last accepted code, to prevent reusing it
+    #[serde(default)]
+    last_step: u64,
+    /// SHA3-256 hashes of the unused recovery codes
+    #[serde(default)]
+    recovery_codes: Vec<String>,
+}
+
+/// A personal API token, which grants the same permissions as its account
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub id: Uuid,
+    pub name: String,
+    /// SHA3-256 hash of the token, the token itself is only shown once
+    token_hash: String,
+    pub created: DateTime<Utc>,
+}
+
+/// A single-use invite to register a new account with the given role
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Invite {
+    pub id: Uuid,
+    /// SHA3-256 hash of the invite token, the token itself is only shown once
+    token_hash: String,
+    pub role: Role,
+    pub created_by: Uuid,
+    pub created: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+    pub used_by: Option<Uuid>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct UserStore {
+    #[serde(default)]
+    users: Vec<Account>,
+    #[serde(default)]
+    invites: Vec<Invite>,
+}
+
+impl Account {
+    fn new(name: String, password_hash: String, role: Role) -> Account {
+        Account {
+            id: Uuid::new_v4(),
+            name,
+            password_hash,
+            role,
+            admin: false,
+            disabled: false,
+            created: Utc::now(),
+            tokens: Vec::new(),
+            two_factor: None,
+        }
+    }
+
+    pub fn has_two_factor(&self) -> bool {
+        self.two_factor.as_ref().is_some_and(|t| t.enabled)
+    }
+
+    /// Admins always need a second factor to log in, other users only if they set one up
+    pub fn requires_two_factor(&self) -> bool {
+        self.role == Role::Admin || self.has_two_factor()
+    }
+
+    pub fn recovery_codes_left(&self) -> usize {
+        self.two_factor.as_ref().map_or(0, |t| t.recovery_codes.len())
+    }
+
+    pub fn tokens(&self) -> &[ApiToken] {
+        &self.tokens
+    }
+}
+
+impl Invite {
+    pub fn is_expired(&self) -> bool {
+        self.expires <= Utc::now()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.used_by.is_none() && !self.is_expired()
+    }
+}
+
+impl UserStore {
+    fn deserialize<P: AsRef<Path>>(path: P) -> Result<UserStore> {
+        let toml_str = fs::read_to_string(path)?;
+        let store = toml::from_str(toml_str.as_str())?;
+        Ok(store)
+    }
+
+    fn serialize<P: AsRef<Path>>(path: P, store: &UserStore) -> Result<()> {
+        let toml_str = toml::to_string_pretty(store)?;
+        fs::create_dir_all("data/")?;
+        fs::write(path, toml_str)?;
+
+        Ok(())
+    }
+
+    fn write(&self) -> Result<()> {
+        Self::serialize(FILE, self)
+    }
+
+    pub fn accounts(&self) -> &[Account] {
+        &self.users
+    }
+
+    pub fn get(&self, id: Uuid) -> Option<&Account> {
+        self.users.iter().find(|u| u.id == id)
+    }
+
+    fn get_mut(&mut self, id: Uuid) -> Option<&mut Account> {
+        self.users.iter_mut().find(|u| u.id == id)
+    }
+
+    pub fn find_by_name(&self, name: &str) -> Option<&Account> {
+        self.users.iter().find(|u| u.name == name)
+    }
+
+    /// The id and password hash of an enabled account, so the password can be verified without holding the store
+    pub fn password_hash(&self, name: &str) -> Option<(Uuid, String)> {
+        self.users.iter()
+            .find(|u| u.name == name && !u.disabled)
+            .map(|u| (u.id, u.password_hash.clone()))
+    }
+
+    /// Replace an outdated password hash, unless the password was changed since it was verified
+    pub fn upgrade_password_hash(&mut self, id: Uuid, old_hash: &str, new_hash: String) -> Result<()> {
+        let account = some_or_throw!(self.get_mut(id), "User not found");
+        if account.password_hash != old_hash {
+            return Ok(());
+        }
+        account.password_hash = new_hash;
+
+        self.write()
+    }
+
+    /// Create an account with the hash of its password, see [`password::hash_new`]
+    pub fn create(&mut self, name: &str, password_hash: String, role: Role) -> Result<Uuid> {
+        let id = self.add_account(name, password_hash, role)?;
+        self.write()?;
+
+        Ok(id)
+    }
+
+    fn add_account(&mut self, name: &str, password_hash: String, role: Role) -> Result<Uuid> {
+        let name = name.trim();
+        if name.is_empty() {
+            throw!("The user name can't be empty");
+        }
+        if self.find_by_name(name).is_some() {
+            throw!("A user named '{name}' already exists");
+        }
+
+        let account = Account::new(name.to_string(), password_hash, role);
+        let id = account.id;
+        self.users.push(account);
+
+        Ok(id)
+    }
+
+    /// Replace the password hash of an account, unless the password was changed since the current one was verified
+    pub fn change_password(&mut self, id: Uuid, old_hash: &str, new_hash: String) -> Result<()> {
+        let account = some_or_throw!(self.get_mut(id), "User not found");
+        if account.password_hash != old_hash {
+            throw!("The password was changed in the meantime");
+        }
+        account.password_hash = new_hash;
+
+        self.write()
+    }
+
+    pub fn set_disabled(&mut self, id: Uuid, disabled: bool) -> Result<()> {
+        let account = some_or_throw!(self.get_mut(id), "User not found");
+        account.disabled = disabled;
+
+        self.write()
+    }
+
+    pub fn set_role(&mut self, id: Uuid, role: Role) -> Result<()> {
+        let account = some_or_throw!(self.get_mut(id), "User not found");
+        account.role = role;
+
+        self.write()
+    }
+
+    pub fn delete(&mut self, id: Uuid) -> Result<()> {
+        let len = self.users.len();
+        self.users.retain(|u| u.id != id);
+        if self.users.len() == len {
+            throw!("User not found");
+        }
+
+        self.write()
+    }
+
+    /// Create a new API token for the given account, returning the token
+    pub fn create_token(&mut self, id: Uuid, name: &str) -> Result<String> {
+        let name = name.trim();
+        if name.is_empty() {
+            throw!("The token name can't be empty");
+        }
+
+        let account = some_or_throw!(self.get_mut(id), "User not found");
+        let token = format!("{API_TOKEN_PREFIX}{}", util::random_string(API_TOKEN_LENGTH));
+        account.tokens.push(ApiToken {
+            id: Uuid::new_v4(),
+            name: name.to_string(),
+            token_hash: util::sha3_256(&token),
+            created: Utc::now(),
+        });
+        self.write()?;
+
+        Ok(token)
+    }
+
+    pub fn revoke_token(&mut self, id: Uuid, token_id: Uuid) -> Result<()> {
+        let account = some_or_throw!(self.get_mut(id), "User not found");
+        let len = account.tokens.len();
+        account.tokens.retain(|t| t.id != token_id);
+        if account.tokens.len() == len {
+            throw!("Token not found");
+        }
+
+        self.write()
+    }
+
+    /// Find the enabled account owning the given API token
+    pub fn authenticate_token(&self, token: &str) -> Option<&Account> {
+        if !token.starts_with(API_TOKEN_PREFIX) {
+            return None;
+        }
+
+        let token_hash = util::sha3_256(token);
+        self.users.iter()
+            .find(|u| u.tokens.iter().any(|t| t.token_hash == token_hash))
+            .filter(|u| !u.disabled)
+    }
+
+    /// Start setting up two-factor authentication, returning the new TOTP secret
+    pub fn start_two_factor_setup(&mut self, id: Uuid) -> Result<String> {
+        let account = some_or_throw!(self.get_mut(id), "User not found");
+        match &account.two_factor {
+            Some(t) if t.enabled => throw!("Two-factor authentication is already enabled"),
+            // Keep the pending secret, in case it was already added to an authenticator app
+            Some(t) => return Ok(t.secret.clone()),
+            None => {}
+        }
+
+        let secret = totp::generate_secret();
+        account.two_factor = Some(TwoFactor {
+            secret: secret.clone(),
+            enabled: false,
+            last_step: 0,
+            recovery_codes: Vec::new(),
+        });
+        self.write()?;
+
+        Ok(secret)
+    }
+
+    /// Enable two-factor authentication if the code is valid, returning the new recovery codes
+    pub fn confirm_two_factor_setup(&mut self, id: Uuid, code: &str) -> Result<Vec<String>> {
+        let account = some_or_throw!(self.get_mut(id), "User not found");
+        let two_factor = some_or_throw!(account.two_factor.as_mut().filter(|t| !t.enabled),
+            "Two-factor authentication setup wasn't started");
+        let step = some_or_throw!(totp::verify(&two_factor.secret, code, two_factor.last_step), "Invalid code");
+
+        let codes = totp::generate_recovery_codes();
+        two_factor.enabled = true;
+        two_factor.last_step = step;
+        two_factor.recovery_codes = codes.iter().map(util::sha3_256).collect();
+        self.write()?;
+
+        Ok(codes)
+    }
+
+    /// Check a TOTP or recovery code, which can't be used again
+    pub fn verify_two_factor(&mut self, id: Uuid, code: &str) -> Result<bool> {
+        let account = some_or_throw!(self.get_mut(id), "User not found");
+        let two_factor = some_or_throw!(account.two_factor.as_mut().filter(|t| t.enabled),
+            "Two-factor authentication isn't enabled");
+
+        if let Some(step) = totp::verify(&two_factor.secret, code, two_factor.last_step) {
+            two_factor.last_step = step;
+        } else {
+            let hash = util::sha3_256(totp::normalize_recovery_code(code));
+            let len = two_factor.recovery_codes.len();
+            two_factor.recovery_codes.retain(|c| *c != hash);
+            if two_factor.recovery_codes.len() == len {
+                return Ok(false);
+            }
+        }
+
+        self.write()?;
+        Ok(true)
+    }
+
+    pub fn regenerate_recovery_codes(&mut self, id: Uuid) -> Result<Vec<String>> {
+        let account = some_or_throw!(self.get_mut(id), "User not found");
+        let two_factor = some_or_throw!(account.two_factor.as_mut().filter(|t| t.enabled),
+            "Two-factor authentication isn't enabled");
+
+        let codes = totp::generate_recovery_codes();
+        two_factor.recovery_codes = codes.iter().map(util::sha3_256).collect();
+        self.write()?;
+
+        Ok(codes)
+    }
+
+    /// Remove the two-factor authentication of an account. Admins will have to set it up again on their next login
+    pub fn reset_two_factor(&mut self, id: Uuid) -> Result<()> {
+        let account = some_or_throw!(self.get_mut(id), "User not found");
+        account.two_factor = None;
+
+        self.write()
+    }
+
+    pub fn invites(&self) -> &[Invite] {
+        &self.invites
+    }
+
+    /// Create a new invite, returning its token
+    pub fn create_invite(&mut self, role: Role, created_by: Uuid, valid_for: Duration) -> Result<String> {
+        let token = util::random_string(INVITE_TOKEN_LENGTH);
+        let now = Utc::now();
+
+        self.invites.push(Invite {
+            id: Uuid::new_v4(),
+            token_hash: util::sha3_256(&token),
+            role,
+            created_by,
+            created: now,
+            expires: now + valid_for,
+            used_by: None,
+        });
+        self.write()?;
+
+        Ok(token)
+    }
+
+    pub fn find_invite(&self, token: &str) -> Option<&Invite> {
+        let token_hash = util::sha3_256(token);
+        self.invites.iter().find(|i| i.token_hash == token_hash && i.is_valid())
+    }
+
+    pub fn delete_invite(&mut self, id: Uuid) -> Result<()> {
+        let len = self.invites.len();
+        self.invites.retain(|i| i.id != id);
+        if self.invites.len() == len {
+            throw!("Invite not found");
+        }
+
+        self.write()
+    }
+
+    /// Create a new account using an invite, which can't be used again
+    pub fn register(&mut self, token: &str, name: &str, password_hash: String) -> Result<Uuid> {
+        let token_hash = util::sha3_256(token);
+        let index = some_or_throw!(self.invites.iter().position(|i| i.token_hash == token_hash && i.is_valid()),
+            "Invalid or expired invite");
+
+        let id = self.add_account(name, password_hash, self.invites[index].role)?;
+        self.invites[index].used_by = Some(id);
+        self.write()?;
+
+        Ok(id)
+    }
+}
+
+/// Load the user store, creating the initial admin account from the `USER` and `PASSWORD_HASH`
+/// environment variables if there are no users yet
+///
+/// `PASSWORD_HASH` may be a legacy SHA3-256 hash, which is replaced on the first login
+pub fn load_users() -> Result<UserStore> {
+    let path = Path::new(FILE);
+    let mut store = if path.exists() {
+        UserStore::deserialize(path)?
+    } else {
+        UserStore::default()
+    };
+
+    if store.users.iter().any(|u| u.admin) {
+        for account in store.users.iter_mut().filter(|u| u.admin) {
+            account.role = Role::Admin;
+            account.admin = false;
+        }
+        store.write()?;
+    }
+
+    if store.users.is_empty() {
+        if let (Ok(user), Ok(password_hash)) = (env::var("USER"), env::var("PASSWORD_HASH")) {
+            println!("Creating the initial admin account '{user}'");
+            store.users.push(Account::new(user, password_hash, Role::Admin));
+            store.write()?;
+        }
+    }
+
+    Ok(store)
+}
@@ -2,6 +2,7 @@ use std::fs::File;
 use std::io::{BufReader, Error as IoError, Read};
 use std::path::Path;
 
+use rand::distributions::{Alphanumeric, DistString};
 use sha2::{Digest, Sha256};
 use sha2::digest::consts::U32;
 use sha2::digest::generic_array::GenericArray;
@@ -54,4 +55,14 @@ pub fn sha3_256<T: AsRef<[u8]>>(input: T) -> String {
     hasher.update(input);
     let result = Digest::finalize(hasher);
     format!("{:x}", result)
-}
\ No newline at end of file
+}
+
+/// Generate a random alphanumeric string, suitable for secret tokens
+pub fn random_string(len: usize) -> String {
+    Alphanumeric.sample_string(&mut rand::thread_rng(), len)
+}
+
+/// Compare two strings in a time that only depends on their length
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}